@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    engine::{
+        CrashBreaker, Engine, EngineKind, EngineParameters, LogFormat, MemoryGuard, OnOutOfRange,
+        OptionOverride, SearchLimit, SearchLimitPolicy, SpawnRetry, Timeouts, UnknownOptionPolicy,
+    },
+    profile::Profile,
+    uci::UciOptionName,
+};
+
+/// Engine configuration, as read from a TOML or JSON file.
+///
+/// ```toml
+/// engine = "/usr/bin/stockfish"
+/// max_threads = 8
+/// max_hash = 2048
+///
+/// [timeouts]
+/// handshake = 60
+///
+/// [options]
+/// "Move Overhead" = "100"
+///
+/// [[profiles]]
+/// name = "deep"
+/// threads = 8
+/// depth = 40
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub engine: PathBuf,
+    /// Command line arguments for the engine.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Wrapper command for sandboxing, like `["firejail", "--quiet"]`.
+    pub sandbox: Option<Vec<String>>,
+    /// `alpha_beta` (default) or `neural_net`. Neural network engines may
+    /// stay silent while loading their network.
+    #[serde(default = "alpha_beta")]
+    pub kind: EngineKind,
+    /// Defaults to the limit advertised by the engine.
+    #[serde(default = "unlimited")]
+    pub max_threads: u32,
+    /// Defaults to the limit advertised by the engine.
+    #[serde(default = "unlimited")]
+    pub max_hash: u32,
+    pub max_hash_fraction: Option<f64>,
+    #[serde(default)]
+    pub blocked_options: Vec<String>,
+    #[serde(default)]
+    pub overlay_options: Vec<String>,
+    #[serde(default = "reject")]
+    pub on_out_of_range: OnOutOfRange,
+    /// Set to `error` to catch typos in the names of initial options.
+    #[serde(default = "ignore")]
+    pub unknown_option_policy: UnknownOptionPolicy,
+    #[serde(default)]
+    pub synthetic_bestmove_on_exit: bool,
+    #[serde(default = "yes")]
+    pub supports_ucinewgame: bool,
+    #[serde(default)]
+    pub always_send_stop: bool,
+    #[serde(default)]
+    pub auto_stop_on_go: bool,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default = "plain")]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub log_raw_lines: bool,
+    #[serde(default)]
+    pub quiet_options: bool,
+    pub max_pv_length: Option<usize>,
+    pub max_nodes: Option<u64>,
+    #[serde(default)]
+    pub ready_before_go: bool,
+    /// Send `isready` with a `go` that follows a `bestmove`, for engines
+    /// that print `info` after `bestmove`.
+    #[serde(default)]
+    pub sync_after_bestmove: bool,
+    /// Stop any search after this many seconds.
+    pub max_search_time: Option<u64>,
+    /// Do not stop searches before this many milliseconds.
+    pub min_search_time: Option<u64>,
+    /// Run at most this many searches at the same time. The limit applies
+    /// to the engine built from this config. Share a [`SearchLimit`] between
+    /// the [`EngineParameters`] of several engines for a global limit.
+    pub max_searches: Option<usize>,
+    /// `wait` (default) or `reject` searches beyond `max_searches`.
+    #[serde(default = "wait")]
+    pub search_limit_policy: SearchLimitPolicy,
+    /// Send at most this many moves in the position command.
+    pub max_position_moves: Option<usize>,
+    #[serde(default)]
+    pub reject_illegal_bestmove: bool,
+    pub spawn_retry: Option<SpawnRetryConfig>,
+    pub crash_breaker: Option<CrashBreakerConfig>,
+    pub memory_guard: Option<MemoryGuardConfig>,
+    #[serde(default)]
+    pub warmup: bool,
+    /// Initial values for engine options.
+    #[serde(default)]
+    pub options: HashMap<String, String>,
+    /// Named option sets that can be switched to at runtime.
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+}
+
+/// Timeouts in seconds. Missing entries keep the default, 0 waits
+/// indefinitely.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub handshake: Option<u64>,
+    pub isready: Option<u64>,
+    pub stop: Option<u64>,
+    pub silent: Option<u64>,
+    pub write: Option<u64>,
+}
+
+/// Retry starting the engine `attempts` times after transient errors,
+/// waiting `backoff` milliseconds before the first retry, and twice as long
+/// before each further retry. `errnos` defaults to `ETXTBSY`, `EMFILE`,
+/// `ENFILE`, and `EAGAIN`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpawnRetryConfig {
+    pub attempts: u32,
+    #[serde(default = "spawn_backoff")]
+    pub backoff: u64,
+    pub errnos: Option<Vec<i32>>,
+}
+
+fn spawn_backoff() -> u64 {
+    100
+}
+
+/// Quarantine the engine after `max_crashes` crashes within `window`
+/// seconds, for `cooldown` seconds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrashBreakerConfig {
+    pub max_crashes: usize,
+    #[serde(default = "crash_window")]
+    pub window: u64,
+    #[serde(default = "crash_cooldown")]
+    pub cooldown: u64,
+}
+
+/// Quit the engine if it uses more than `max_resident` MiB of memory,
+/// checked every `interval` seconds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryGuardConfig {
+    pub max_resident: u64,
+    #[serde(default = "memory_check_interval")]
+    pub interval: u64,
+}
+
+fn memory_check_interval() -> u64 {
+    5
+}
+
+fn crash_window() -> u64 {
+    60
+}
+
+fn crash_cooldown() -> u64 {
+    300
+}
+
+fn alpha_beta() -> EngineKind {
+    EngineKind::AlphaBeta
+}
+
+fn unlimited() -> u32 {
+    u32::MAX
+}
+
+fn reject() -> OnOutOfRange {
+    OnOutOfRange::Reject
+}
+
+fn ignore() -> UnknownOptionPolicy {
+    UnknownOptionPolicy::Ignore
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn wait() -> SearchLimitPolicy {
+    SearchLimitPolicy::Wait
+}
+
+fn plain() -> LogFormat {
+    LogFormat::Plain
+}
+
+fn seconds(configured: Option<u64>, default: Option<Duration>) -> Option<Duration> {
+    match configured {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => default,
+    }
+}
+
+impl Config {
+    /// Reads a config file. The format is selected by the extension, `.toml`
+    /// or `.json`.
+    pub fn from_path(path: &Path) -> io::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Some("json") => serde_json::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected .toml or .json config file",
+            )),
+        }
+    }
+
+    pub fn parameters(&self) -> EngineParameters {
+        let defaults = Timeouts::default();
+        EngineParameters {
+            args: self.args.clone(),
+            sandbox: self.sandbox.clone(),
+            kind: self.kind,
+            max_threads: self.max_threads,
+            max_hash: self.max_hash,
+            max_hash_fraction: self.max_hash_fraction,
+            blocked_options: self
+                .blocked_options
+                .iter()
+                .cloned()
+                .map(UciOptionName)
+                .collect(),
+            overlay_options: self
+                .overlay_options
+                .iter()
+                .cloned()
+                .map(UciOptionName)
+                .collect(),
+            on_out_of_range: self.on_out_of_range,
+            unknown_option_policy: self.unknown_option_policy,
+            synthetic_bestmove_on_exit: self.synthetic_bestmove_on_exit,
+            supports_ucinewgame: self.supports_ucinewgame,
+            always_send_stop: self.always_send_stop,
+            auto_stop_on_go: self.auto_stop_on_go,
+            timeouts: Timeouts {
+                handshake: seconds(self.timeouts.handshake, defaults.handshake),
+                isready: seconds(self.timeouts.isready, defaults.isready),
+                stop: seconds(self.timeouts.stop, defaults.stop),
+                silent: seconds(self.timeouts.silent, defaults.silent),
+                write: seconds(self.timeouts.write, defaults.write),
+            },
+            log_format: self.log_format,
+            log_raw_lines: self.log_raw_lines,
+            quiet_options: self.quiet_options,
+            max_pv_length: self.max_pv_length,
+            max_nodes: self.max_nodes,
+            ready_before_go: self.ready_before_go,
+            sync_after_bestmove: self.sync_after_bestmove,
+            max_search_time: self.max_search_time.map(Duration::from_secs),
+            min_search_time: self.min_search_time.map(Duration::from_millis),
+            search_limit: self
+                .max_searches
+                .map(|searches| SearchLimit::new(searches, self.search_limit_policy)),
+            position_transform: None,
+            max_position_moves: self.max_position_moves,
+            reject_illegal_bestmove: self.reject_illegal_bestmove,
+            rlimits: None,
+            spawn_retry: self.spawn_retry.as_ref().map(|retry| SpawnRetry {
+                attempts: retry.attempts,
+                backoff: Duration::from_millis(retry.backoff),
+                errnos: retry
+                    .errnos
+                    .clone()
+                    .unwrap_or_else(SpawnRetry::transient_errnos),
+            }),
+            crash_breaker: self.crash_breaker.as_ref().map(|breaker| CrashBreaker {
+                max_crashes: breaker.max_crashes,
+                window: Duration::from_secs(breaker.window),
+                cooldown: Duration::from_secs(breaker.cooldown),
+            }),
+            memory_guard: self.memory_guard.as_ref().map(|guard| MemoryGuard {
+                max_resident: guard.max_resident * 1024 * 1024,
+                interval: Duration::from_secs(guard.interval),
+            }),
+            recorder: None,
+            warmup: self.warmup,
+        }
+    }
+
+    /// The initial options, for [`Engine::new()`].
+    pub fn option_overrides(&self) -> HashMap<UciOptionName, OptionOverride> {
+        self.options
+            .iter()
+            .map(|(name, value)| {
+                (
+                    UciOptionName(name.clone()),
+                    OptionOverride::Value(value.clone()),
+                )
+            })
+            .collect()
+    }
+
+    pub async fn into_engine(self) -> io::Result<Engine> {
+        let params = self.parameters();
+        let options = self.option_overrides();
+        Engine::new(self.engine, params, options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema() {
+        let config: Config = toml::from_str(
+            r#"
+            engine = "/usr/bin/stockfish"
+            args = ["--config", "stockfish.ini"]
+            max_threads = 8
+            blocked_options = ["Debug Log File"]
+            on_out_of_range = "clamp"
+            unknown_option_policy = "error"
+            log_format = "json"
+            max_nodes = 1000000
+            max_search_time = 30
+            max_searches = 2
+            search_limit_policy = "reject"
+
+            [timeouts]
+            handshake = 60
+            stop = 0
+
+            [spawn_retry]
+            attempts = 2
+
+            [crash_breaker]
+            max_crashes = 3
+
+            [memory_guard]
+            max_resident = 4096
+
+            [options]
+            "Move Overhead" = "100"
+
+            [[profiles]]
+            name = "teaching"
+            skill_level = 5
+            movetime = 500
+            "#,
+        )
+        .expect("valid toml");
+        let params = config.parameters();
+        assert_eq!(params.args, ["--config", "stockfish.ini"]);
+        assert_eq!(params.max_threads, 8);
+        assert_eq!(params.max_hash, u32::MAX);
+        assert_eq!(params.on_out_of_range, OnOutOfRange::Clamp);
+        assert_eq!(params.unknown_option_policy, UnknownOptionPolicy::Error);
+        assert_eq!(params.log_format, LogFormat::Json);
+        assert_eq!(params.max_nodes, Some(1_000_000));
+        assert_eq!(params.max_search_time, Some(Duration::from_secs(30)));
+        assert!(params.supports_ucinewgame);
+        let limit = params.search_limit.expect("search limit");
+        assert_eq!(limit.permits.available_permits(), 2);
+        assert_eq!(limit.policy, SearchLimitPolicy::Reject);
+        assert_eq!(params.timeouts.handshake, Some(Duration::from_secs(60)));
+        assert_eq!(params.timeouts.isready, Timeouts::default().isready);
+        assert_eq!(params.timeouts.stop, None);
+        let retry = params.spawn_retry.expect("spawn retry");
+        assert_eq!(retry.attempts, 2);
+        assert_eq!(retry.errnos, SpawnRetry::transient_errnos());
+        let breaker = params.crash_breaker.expect("crash breaker");
+        assert_eq!(breaker.max_crashes, 3);
+        assert_eq!(breaker.cooldown, Duration::from_secs(300));
+        let guard = params.memory_guard.expect("memory guard");
+        assert_eq!(guard.max_resident, 4096 * 1024 * 1024);
+        assert_eq!(guard.interval, Duration::from_secs(5));
+        assert_eq!(config.options["Move Overhead"], "100");
+        assert_eq!(config.profiles[0].name, "teaching");
+        assert_eq!(config.profiles[0].go().to_string(), "go movetime 500");
+
+        let config: Config = serde_json::from_str(
+            r#"{"engine": "lc0", "kind": "neural_net", "supports_ucinewgame": false, "options": {"Hash": "64"}}"#,
+        )
+        .expect("valid json");
+        assert_eq!(config.parameters().timeouts.handshake, None);
+        assert!(!config.parameters().supports_ucinewgame);
+        assert_eq!(
+            config.parameters().unknown_option_policy,
+            UnknownOptionPolicy::Ignore
+        );
+
+        assert!(toml::from_str::<Config>("engine = \"stockfish\"\nmax_thread = 4").is_err());
+        assert!(toml::from_str::<Config>("max_threads = 4").is_err());
+    }
+}
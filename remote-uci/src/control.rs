@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Path,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    engine::{BreakerState, Session},
+    ws::{Secret, SharedEngine},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    session: u64,
+    in_use: bool,
+    active_sessions: Vec<u64>,
+    /// Unknown while a session is holding the engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crash_breaker: Option<CrashBreakerStatus>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashBreakerStatus {
+    recent_crashes: usize,
+    /// Seconds until restarts are allowed again, if quarantined.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_in: Option<u64>,
+}
+
+impl From<BreakerState> for CrashBreakerStatus {
+    fn from(state: BreakerState) -> CrashBreakerStatus {
+        match state {
+            BreakerState::Closed { recent_crashes } => CrashBreakerStatus {
+                recent_crashes,
+                retry_in: None,
+            },
+            BreakerState::Open { retry_in } => CrashBreakerStatus {
+                recent_crashes: 0,
+                retry_in: Some(retry_in.as_secs_f64().ceil() as u64),
+            },
+        }
+    }
+}
+
+fn authorize(token: &Secret, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if *token == Secret(provided.to_owned()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+pub async fn status(
+    shared_engine: Arc<SharedEngine>,
+    token: Secret,
+    headers: HeaderMap,
+) -> Result<Json<Status>, StatusCode> {
+    authorize(&token, &headers)?;
+    Ok(Json(Status {
+        session: shared_engine.current_session().0,
+        in_use: shared_engine.is_in_use(),
+        active_sessions: shared_engine
+            .active_sessions()
+            .into_iter()
+            .map(|session| session.0)
+            .collect(),
+        crash_breaker: shared_engine.breaker_state().map(CrashBreakerStatus::from),
+    }))
+}
+
+pub async fn stop(
+    shared_engine: Arc<SharedEngine>,
+    token: Secret,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&token, &headers)?;
+    let session = shared_engine.end_session();
+    log::warn!("{}: session ended by control request", session.0);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn cancel(
+    shared_engine: Arc<SharedEngine>,
+    token: Secret,
+    headers: HeaderMap,
+    Path(session): Path<u64>,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&token, &headers)?;
+    if shared_engine.cancel_session(Session(session)) {
+        log::warn!("{}: session cancelled by control request", session);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let token = Secret("0123456789abcdef".to_owned());
+        let mut headers = HeaderMap::new();
+        assert_eq!(authorize(&token, &headers), Err(StatusCode::UNAUTHORIZED));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer 0123456789abcdee"),
+        );
+        assert_eq!(authorize(&token, &headers), Err(StatusCode::UNAUTHORIZED));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("0123456789abcdef"));
+        assert_eq!(authorize(&token, &headers), Err(StatusCode::UNAUTHORIZED));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer 0123456789abcdef"),
+        );
+        assert_eq!(authorize(&token, &headers), Ok(()));
+    }
+}
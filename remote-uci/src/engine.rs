@@ -1,240 +1,5168 @@
-use std::{collections::HashMap, io, path::PathBuf, process::Stdio};
+use std::{
+    cmp::min,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    io, mem,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::Level;
+use serde::Deserialize;
+use sysinfo::{RefreshKind, System, SystemExt};
 
+use thiserror::Error;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    process::{ChildStdin, ChildStdout, Command},
+    io::{duplex, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+    process::{Child, Command},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    time::timeout_at,
+};
+
+use shakmaty::{fen::Fen, uci::Uci, CastlingMode, Chess, EnPassantMode, Position};
+
+use crate::{
+    profile::Profile,
+    transcript::{SessionRecorder, Transcript, TranscriptDirection},
+    uci::{Score, UciIn, UciOption, UciOptionName, UciOut},
 };
 
-use crate::uci::{UciIn, UciOption, UciOptionName, UciOut};
+#[cfg(feature = "stockfish")]
+use crate::uci::{BenchStat, EvalKind};
+
+/// Upper bound for outstanding `isready` commands. Additional `isready`
+/// commands are collapsed into the pending ones.
+const MAX_PENDING_READYOK: u64 = 8;
+
+/// Upper bound for the number of banner lines kept.
+const MAX_BANNER_LINES: usize = 32;
+
+/// Give up on the handshake after this many lines of output without any
+/// recognizable UCI command.
+const MAX_NON_UCI_LINES: usize = 16;
+
+/// Options that shape the analysis of a single client, rather than the
+/// engine as a whole. They are reset to their initial values at the start of
+/// each session, see [`Engine::ensure_newgame()`]. All other options persist
+/// across sessions.
+const SESSION_OPTIONS: [&str; 5] = [
+    "MultiPV",
+    "UCI_AnalyseMode",
+    "UCI_LimitStrength",
+    "UCI_Elo",
+    "UCI_ShowWDL",
+];
+
+/// Entries of `UCI_Variant` that are not playable games. Fairy-Stockfish
+/// advertises `fairy`, a test variant with all fairy pieces.
+const NON_GAME_VARIANTS: [&str; 1] = ["fairy"];
+
+/// Hash size (MiB) used for deterministic analysis.
+const DETERMINISTIC_HASH: i64 = 16;
+
+/// How long an engine that was asked to quit gets to exit before it is
+/// killed, unless [`Timeouts::stop`] is set.
+const QUIT_GRACE: Duration = Duration::from_secs(10);
+
+/// Upper bound for the depth of [`Engine::perft()`]. Engines do not stop
+/// perft early, and the tree grows exponentially.
+const MAX_PERFT_DEPTH: u32 = 7;
+
+type CommandFilter = Box<dyn FnMut(&mut UciIn) -> Decision + Send>;
+
+type OutputTee = Box<dyn FnMut(UciOut) + Send>;
+
+type EngineStdin = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+type EngineStdout = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+/// Write and read half of a connection to an engine, as opened by a
+/// [`Transport`].
+pub type Connection = (
+    Box<dyn AsyncWrite + Send + Unpin>,
+    Box<dyn AsyncRead + Send + Unpin>,
+);
+
+/// A way to reach an engine that is not a local process. The transport is
+/// asked for a fresh connection whenever the engine is restarted.
+pub trait Transport: fmt::Debug + Send + Sync {
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Connection>> + Send + '_>>;
+}
+
+/// Talks UCI over a TCP stream at `addr` (`host:port`).
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    pub addr: String,
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Connection>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(&self.addr).await?;
+            stream.set_nodelay(true)?;
+            let (read, write) = stream.into_split();
+            Ok::<Connection, io::Error>((Box::new(write), Box::new(read)))
+        })
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub struct Session(pub u64);
 
+/// Errors specific to the engine layer. They are wrapped in [`io::Error`],
+/// so that callers can distinguish them with [`io::Error::get_ref`].
+#[derive(Error, Debug)]
+pub enum EngineError {
+    #[error("engine exited")]
+    EngineExited,
+    #[error("unknown option: {0}")]
+    UnknownOption(UciOptionName),
+    #[error("too many concurrent searches")]
+    TooManySearches,
+    #[error("draining")]
+    Draining,
+    #[error("engine silent during search")]
+    Silent,
+    #[error("does not appear to be a UCI engine, output: {0:?}")]
+    NotUci(Vec<String>),
+    #[error("illegal bestmove: {0}")]
+    IllegalBestmove(Uci),
+    #[error("engine quarantined after repeated crashes, retry in {0:?}")]
+    Quarantined(Duration),
+    #[error("engine used {0} bytes of memory, more than allowed")]
+    OutOfMemory(u64),
+    #[error("variant not supported: {0}")]
+    UnsupportedVariant(String),
+    #[error("option cannot be set per session: {0}")]
+    NotOverlayOption(UciOptionName),
+}
+
+/// Problem with an option found by [`EngineProbe::validate_options()`].
+#[derive(Error, Debug)]
+pub enum OptionError {
+    #[error("unknown option: {0}")]
+    Unknown(UciOptionName),
+    #[error("invalid value for option {name}: {value:?}")]
+    InvalidValue { name: UciOptionName, value: String },
+}
+
+/// Initial value for an engine option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionOverride {
+    /// Set the option, if the engine advertises it.
+    Value(String),
+    /// Explicitly leave the option at the engine default.
+    Default,
+    /// Set the option, failing engine startup if the engine does not
+    /// advertise it.
+    Required(String),
+}
+
 pub struct Engine {
     pending_uciok: u64,
     pending_readyok: u64,
     searching: bool,
+    last_sent: Option<String>,
+    last_received: Option<String>,
+    /// Output read so far, kept when receiving is cancelled in the middle of
+    /// a line. Read with `read_until()`, which unlike `read_line()` does not
+    /// lose data when cancelled.
+    partial_line: Vec<u8>,
+    log_seq: u64,
     options: HashMap<UciOptionName, UciOption>,
+    /// Options advertised again since the table was last refreshed, see
+    /// [`Engine::refresh_options()`].
+    refreshed_options: Option<HashSet<UciOptionName>>,
+    values: HashMap<UciOptionName, Option<String>>,
+    session_values: HashMap<UciOptionName, Option<String>>,
+    overlays: HashMap<Session, HashMap<UciOptionName, Option<String>>>,
+    overlay_base: HashMap<UciOptionName, Option<String>>,
+    overlay_session: Option<Session>,
+    position: Option<UciIn>,
+    search_summary: SearchSummary,
+    search_permit: Option<OwnedSemaphorePermit>,
+    search_owner: Option<Session>,
+    search_started: Option<Instant>,
+    search_generation: u64,
+    after_bestmove: bool,
+    /// Searches of `bench` are running.
+    benchmarking: bool,
+    stale_readyok: u64,
+    last_search_wallclock: Option<Duration>,
+    silent_stop_sent: bool,
+    capped_stop_sent: bool,
+    /// A `stop` was held back until the minimum search time.
+    deferred_stop: bool,
     name: Option<String>,
+    author: Option<String>,
+    nnue: Option<String>,
+    banner: Vec<String>,
+    metrics: EngineMetrics,
     params: EngineParameters,
-    stdin: BufWriter<ChildStdin>,
-    stdout: BufReader<ChildStdout>,
+    command_filter: Option<CommandFilter>,
+    output_tee: Option<OutputTee>,
+    tee_noise: bool,
+    path: PathBuf,
+    /// Connection to a remote engine, instead of the local process at
+    /// `path`.
+    transport: Option<Box<dyn Transport>>,
+    exited: bool,
+    stdin_closed: bool,
+    crashes: VecDeque<Instant>,
+    quarantined_until: Option<Instant>,
+    draining: bool,
+    last_activity: Instant,
+    last_memory_check: Instant,
+    child: Option<Child>,
+    stdin: EngineStdin,
+    stdout: EngineStdout,
 }
 
 pub struct EngineParameters {
+    /// Command line arguments for the engine process. They are passed to the
+    /// process directly, not through a shell, so there is no need to quote
+    /// or escape shell metacharacters.
+    pub args: Vec<String>,
+    /// Wrapper command to run the engine in a sandbox, like
+    /// `["bwrap", "--ro-bind", "/", "/", "--unshare-all", "--"]`. The engine
+    /// path and arguments are appended. Resource limits are applied to the
+    /// wrapper and inherited by the engine, unless the wrapper changes them.
+    /// [`Engine::pid()`] is the process ID of the wrapper.
+    pub sandbox: Option<Vec<String>>,
+    pub kind: EngineKind,
     pub max_threads: u32,
     pub max_hash: u32,
+    /// Further limit the hash table to this fraction of the total system
+    /// memory. The smaller of both limits applies.
+    pub max_hash_fraction: Option<f64>,
+    /// Options advertised by the engine that are hidden from clients. They
+    /// are treated as unknown, so they cannot be set at all, not even as
+    /// initial options.
+    pub blocked_options: HashSet<UciOptionName>,
+    /// Options that sessions may set for themselves only, with
+    /// [`Engine::set_session_option()`], like `Skill Level`. They need not
+    /// be safe for clients to set globally, so the operator should only
+    /// allow options that accept any value without harm.
+    pub overlay_options: HashSet<UciOptionName>,
+    pub on_out_of_range: OnOutOfRange,
+    pub unknown_option_policy: UnknownOptionPolicy,
+    /// If the engine exits during a search, report `bestmove (none)` before
+    /// failing with [`EngineError::EngineExited`].
+    pub synthetic_bestmove_on_exit: bool,
+    /// Whether the engine understands `ucinewgame`. If not, the command is
+    /// never sent.
+    pub supports_ucinewgame: bool,
+    /// Write `stop` to the engine even if it is not searching. Otherwise
+    /// such a `stop` is dropped, because some engines complain about it.
+    pub always_send_stop: bool,
+    /// A new `position` or `go` from the session that owns the running
+    /// search stops it, and waits for its `bestmove` (which is discarded),
+    /// instead of failing because the engine is busy.
+    pub auto_stop_on_go: bool,
+    pub timeouts: Timeouts,
+    pub log_format: LogFormat,
+    /// Log every line received from the engine at trace level, exactly as
+    /// read, with control characters like `\r` escaped. For diagnosing
+    /// parsing issues.
+    pub log_raw_lines: bool,
+    /// Log the options advertised by the engine at debug level, and only
+    /// their number at info level, for engines with many options, like
+    /// Fairy-Stockfish.
+    pub quiet_options: bool,
+    /// Truncate principal variations reported to clients to this many moves.
+    /// The search summary keeps the full line.
+    pub max_pv_length: Option<usize>,
+    /// Reduce node budgets requested with `go nodes` to at most this many
+    /// nodes. Other limits of the same `go` are kept.
+    pub max_nodes: Option<u64>,
+    /// Send `isready` before every `go`, and wait for `readyok`, so that
+    /// the engine has certainly processed all previous commands, like a
+    /// slow `setoption`. Adds a round trip to every search.
+    pub ready_before_go: bool,
+    /// Send `isready` along with a `go` that directly follows a `bestmove`.
+    /// Some engines print `info` after `bestmove`, and without the
+    /// `readyok` as a fence, those lines could be mistaken for output of the
+    /// new search. Search output (depth, score, currmove or pv) that arrives
+    /// before the next `go` is discarded either way.
+    pub sync_after_bestmove: bool,
+    /// Hard limit for the duration of any search, including `go infinite`.
+    /// Longer `movetime` requests are reduced, and `stop` is sent once the
+    /// limit expires. The `bestmove` is reported as usual.
+    pub max_search_time: Option<Duration>,
+    /// Hold back a `stop` until the search has run for at least this long,
+    /// so that a client that rapidly starts and stops searches still gets a
+    /// shallow result. The `stop` is then sent, and the `bestmove` reported
+    /// as usual.
+    pub min_search_time: Option<Duration>,
+    /// Limit for concurrent searches, possibly shared with other engines.
+    pub search_limit: Option<SearchLimit>,
+    /// Transforms positions before they are sent to the engine. Moves
+    /// reported by the engine are transformed back. The transformed
+    /// position is sent as a bare FEN, so the engine does not see the moves
+    /// that led to it, and cannot detect repetitions.
+    pub position_transform: Option<PositionTransform>,
+    /// For engines that accept only a limited number of moves in the
+    /// `position` command, like in very long correspondence games. Earlier
+    /// moves are played into a new FEN, and only this many moves are sent,
+    /// so that the engine still sees the recent history. Moves are replayed
+    /// with the same move generator as for [`EngineParameters::position_transform`],
+    /// so this works only in standard chess and Chess960. Positions of other
+    /// variants are sent unchanged.
+    pub max_position_moves: Option<usize>,
+    /// With the `validation` feature, fail with
+    /// [`EngineError::IllegalBestmove`] instead of only logging an error, if
+    /// the engine reports a `bestmove` that is not legal in the current
+    /// position.
+    pub reject_illegal_bestmove: bool,
+    /// Resource limits for the engine process.
+    pub rlimits: Option<RLimits>,
+    pub spawn_retry: Option<SpawnRetry>,
+    /// Stop restarting an engine that keeps crashing.
+    pub crash_breaker: Option<CrashBreaker>,
+    /// Quit the engine if it uses too much memory.
+    pub memory_guard: Option<MemoryGuard>,
+    /// Record a transcript of the session, for [`crate::transcript::replay()`].
+    pub recorder: Option<SessionRecorder>,
+    /// Run a `go depth 1` on the starting position after startup (once the
+    /// initial options are set), so that the first real search does not pay
+    /// for cold caches or loading the network.
+    pub warmup: bool,
 }
 
-impl Engine {
-    pub async fn new(
-        path: PathBuf,
-        params: EngineParameters,
-        options: HashMap<UciOptionName, String>,
-    ) -> io::Result<Engine> {
-        log::info!("Starting engine {path:?} ...");
+/// A pair of functions mapping positions into the frame searched by the
+/// engine, and moves back into the original frame. For example, mirroring
+/// a position has to be paired with mirroring moves.
+pub struct PositionTransform {
+    pub position: Box<dyn Fn(Chess) -> Chess + Send + Sync>,
+    pub uci: Box<dyn Fn(Uci) -> Uci + Send + Sync>,
+}
 
-        let mut process = Command::new(path)
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()?;
+/// What to do with a `setoption` for a spin option, if the value is outside
+/// of the range advertised by the engine.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnOutOfRange {
+    /// Fail with [`crate::uci::ProtocolError::InvalidOptionValue`].
+    Reject,
+    /// Adjust the value to the minimum or maximum.
+    Clamp,
+}
 
-        let mut engine =
-            Engine {
-                pending_uciok: 0,
-                pending_readyok: 0,
-                searching: false,
-                options: HashMap::new(),
-                name: None,
-                params,
-                stdin: BufWriter::new(process.stdin.take().ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::BrokenPipe, "engine stdin closed")
-                })?),
-                stdout: BufReader::new(process.stdout.take().ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::BrokenPipe, "engine stdout closed")
-                })?),
-            };
+/// What to do with a `setoption` for an option that the engine does not
+/// advertise (or that is blocked). `Threads` and `Hash` are always skipped
+/// silently for engines without them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownOptionPolicy {
+    /// Log a warning and skip the command.
+    Ignore,
+    /// Fail with [`EngineError::UnknownOption`], for example to catch typos
+    /// in configured option names.
+    Error,
+}
 
-        let session = Session(0);
-        engine.send(session, UciIn::Uci).await?;
-        engine.ensure_idle(session).await?;
-        for (name, value) in options {
-            engine
-                .send_dangerous(
-                    session,
-                    UciIn::Setoption {
-                        name,
-                        value: Some(value),
-                    },
-                )
-                .await?;
+/// Resource limits for the engine process. Exceeding the address space
+/// limit usually makes the engine crash, which is reported like any other
+/// exit, see [`EngineError::EngineExited`].
+#[derive(Debug, Clone, Default)]
+pub struct RLimits {
+    /// Maximum size of the virtual memory (`RLIMIT_AS`), in bytes.
+    pub address_space: Option<u64>,
+    /// Maximum CPU time (`RLIMIT_CPU`), rounded up to whole seconds.
+    pub cpu_time: Option<Duration>,
+}
+
+impl RLimits {
+    #[cfg(unix)]
+    fn apply(&self, command: &mut Command) {
+        let limits = [
+            (libc::RLIMIT_AS, self.address_space),
+            (
+                libc::RLIMIT_CPU,
+                self.cpu_time
+                    .map(|t| t.as_secs() + u64::from(t.subsec_nanos() > 0)),
+            ),
+        ];
+        // SAFETY: Only calls setrlimit, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                for (resource, limit) in limits {
+                    if let Some(limit) = limit {
+                        let rlimit = libc::rlimit {
+                            rlim_cur: limit as libc::rlim_t,
+                            rlim_max: limit as libc::rlim_t,
+                        };
+                        if libc::setrlimit(resource, &rlimit) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                }
+                Ok(())
+            });
         }
-        Ok(engine)
     }
 
-    pub async fn send(&mut self, session: Session, command: UciIn) -> io::Result<()> {
-        match command {
-            UciIn::Setoption { ref name, .. } if !name.is_safe() => {
-                log::error!(
-                    "{}: rejected potentially unsafe option: {}",
-                    session.0,
-                    command
-                );
-                Ok(())
-            }
-            _ => self.send_dangerous(session, command).await,
+    #[cfg(not(unix))]
+    fn apply(&self, _command: &mut Command) {
+        log::warn!("Resource limits are not supported on this platform");
+    }
+}
+
+/// Retries spawning the engine process after transient failures, like
+/// `ETXTBSY` right after the binary was replaced, or running out of file
+/// descriptors. Other errors, like a missing binary, fail immediately.
+#[derive(Debug, Clone)]
+pub struct SpawnRetry {
+    /// Number of retries after the first attempt.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each further retry.
+    pub backoff: Duration,
+    /// Raw OS error codes that are considered transient.
+    pub errnos: Vec<i32>,
+}
+
+impl SpawnRetry {
+    /// `ETXTBSY`, `EMFILE`, `ENFILE`, and `EAGAIN`.
+    #[cfg(unix)]
+    pub fn transient_errnos() -> Vec<i32> {
+        vec![libc::ETXTBSY, libc::EMFILE, libc::ENFILE, libc::EAGAIN]
+    }
+
+    #[cfg(not(unix))]
+    pub fn transient_errnos() -> Vec<i32> {
+        Vec::new()
+    }
+}
+
+impl Default for SpawnRetry {
+    fn default() -> SpawnRetry {
+        SpawnRetry {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            errnos: SpawnRetry::transient_errnos(),
         }
     }
+}
 
-    pub async fn send_dangerous(&mut self, session: Session, command: UciIn) -> io::Result<()> {
-        match command {
-            UciIn::Isready => self.pending_readyok += 1,
-            UciIn::Stop | UciIn::Ponderhit => (),
-            _ if self.searching => {
-                log::error!("{}: engine is busy: {}", session.0, command);
-                return Err(io::Error::new(io::ErrorKind::Other, "engine is busy"));
-            }
-            UciIn::Uci => {
-                self.pending_uciok += 1;
-                self.options.clear();
-                self.name.take();
-            }
-            UciIn::Go { .. } => {
-                self.searching = true;
-            }
-            UciIn::Setoption {
-                ref name,
-                ref value,
-            } => match self.options.get(name) {
-                Some(option) => {
-                    option
-                        .validate(value.clone())
-                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
-                }
-                None => {
-                    log::warn!("{}: ignoring unknown option: {}", session.0, command);
-                    return Ok(());
-                }
-            },
-            _ => (),
+/// Quarantines an engine that exits unexpectedly `max_crashes` times within
+/// `window`. During the `cooldown`, [`Engine::restart()`] fails with
+/// [`EngineError::Quarantined`] instead of spawning a new process.
+#[derive(Debug, Clone)]
+pub struct CrashBreaker {
+    pub max_crashes: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
+
+/// Checks the resident memory of the engine process every `interval`,
+/// while waiting for engine output, and quits the engine if it uses more
+/// than `max_resident` bytes. The engine can allocate more than the `Hash`
+/// table, for example for threads or the network, so this protects the rest
+/// of the machine before the OOM killer steps in. The pending
+/// [`Engine::recv()`] then fails with [`EngineError::OutOfMemory`], and
+/// the engine can be restarted. This counts as a crash for the
+/// [`CrashBreaker`].
+///
+/// Only supported on Linux. With a sandbox, the memory of the wrapper
+/// process is checked.
+#[derive(Debug, Clone)]
+pub struct MemoryGuard {
+    pub max_resident: u64,
+    pub interval: Duration,
+}
+
+/// State of the [`CrashBreaker`], for monitoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Restarts are allowed.
+    Closed { recent_crashes: usize },
+    /// Restarts fail until the cooldown elapses.
+    Open { retry_in: Duration },
+}
+
+/// Limit of a search, in terms of intent rather than UCI. See
+/// [`Engine::go()`] and [`Engine::analyze_request()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnalysisRequest {
+    ForTime(Duration),
+    ToDepth(u32),
+    ForNodes(u64),
+    /// Until stopped.
+    Infinite,
+    /// Search for a mate in this many moves.
+    Mate(u32),
+}
+
+/// Verdict of a filter installed with [`Engine::set_command_filter()`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// Send the command, including any changes made by the filter.
+    Allow,
+    /// Drop the command, as if it had been sent.
+    Deny,
+}
+
+/// What [`Engine::forward()`] does when the consumer falls behind.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backpressure {
+    /// Wait for the consumer, and stop reading from the engine in the
+    /// meantime. The engine eventually blocks on its own output, slowing
+    /// down the search, but nothing is lost.
+    Block,
+    /// Drop `info` lines that do not fit into the channel. Everything else,
+    /// in particular `bestmove`, is still delivered.
+    DropInfo,
+}
+
+/// Limits the number of concurrent searches across all engines that share
+/// the same semaphore. A permit is held from `go` until `bestmove`, or until
+/// the search fails to start or to stop.
+#[derive(Clone)]
+pub struct SearchLimit {
+    pub permits: Arc<Semaphore>,
+    pub policy: SearchLimitPolicy,
+}
+
+impl SearchLimit {
+    /// Allows `searches` concurrent searches. Clone the limit to share it
+    /// between engines.
+    pub fn new(searches: usize, policy: SearchLimitPolicy) -> SearchLimit {
+        SearchLimit {
+            permits: Arc::new(Semaphore::new(searches)),
+            policy,
         }
+    }
+}
 
-        let mut buf = command.to_string();
-        log::info!("{} << {}", session.0, buf);
-        buf.push_str("\r\n");
-        self.stdin.write_all(buf.as_bytes()).await?;
-        self.stdin.flush().await
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchLimitPolicy {
+    /// Wait until another search completes.
+    Wait,
+    /// Fail with [`EngineError::TooManySearches`].
+    Reject,
+}
+
+impl FromStr for SearchLimitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SearchLimitPolicy, String> {
+        match s {
+            "wait" => Ok(SearchLimitPolicy::Wait),
+            "reject" => Ok(SearchLimitPolicy::Reject),
+            _ => Err(format!("unknown search limit policy: {s}")),
+        }
+    }
+}
+
+/// How long to wait for the engine during each phase of the protocol.
+/// `None` waits indefinitely, which is the default.
+#[derive(Debug, Clone, Default)]
+pub struct Timeouts {
+    /// From `uci` to `uciok`.
+    pub handshake: Option<Duration>,
+    /// From `isready` to `readyok`.
+    pub isready: Option<Duration>,
+    /// From `stop` to `bestmove`.
+    pub stop: Option<Duration>,
+    /// Without any output during a search. When this expires, `stop` is
+    /// sent once. If the engine still stays silent, receiving fails with
+    /// [`EngineError::Silent`].
+    pub silent: Option<Duration>,
+    /// Writing a command to the engine input, which blocks while the engine
+    /// is not reading it. When this expires, the engine is killed, because
+    /// the command may have been written partially, and sending fails with
+    /// [`io::ErrorKind::TimedOut`]. It is restarted like after a crash.
+    pub write: Option<Duration>,
+}
+
+/// Broad family of the engine, for defaults that differ between them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EngineKind {
+    /// Classical search engines like Stockfish.
+    AlphaBeta,
+    /// Engines like Lc0 or Ceres, that load large networks (possibly onto a
+    /// GPU) at startup or on the first search. `UCI_ShowWDL` is enabled, if
+    /// advertised, and the silent watchdog does not apply to the first
+    /// search.
+    NeuralNet,
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EngineKind, String> {
+        match s {
+            "alpha_beta" => Ok(EngineKind::AlphaBeta),
+            "neural_net" => Ok(EngineKind::NeuralNet),
+            _ => Err(format!("unknown engine kind: {s}")),
+        }
     }
+}
 
-    pub async fn recv(&mut self, session: Session) -> io::Result<UciOut> {
-        loop {
-            let mut line = String::new();
-            if self.stdout.read_line(&mut line).await? == 0 {
-                return Err(io::ErrorKind::UnexpectedEof.into());
-            }
-            let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+/// Format of the log lines for commands sent to and received from the
+/// engine.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// `<session> << <line>` and `<session> >> <line>`.
+    Plain,
+    /// One JSON object per line, with `session`, `dir` (`in` for commands
+    /// sent to the engine, `out` for its output), `line`, `ts`
+    /// (milliseconds since the Unix epoch) and `seq`. The sequence number
+    /// counts the lines logged for the engine, so that their exact order is
+    /// known even when timestamps collide.
+    Json,
+}
 
-            let mut command = match UciOut::from_line(line) {
-                Err(err) => {
-                    log::error!("{} >> {}", session.0, line);
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
-                }
-                Ok(None) => {
-                    log::warn!("{} >> {}", session.0, line);
-                    continue;
-                }
-                Ok(Some(command)) => command,
-            };
+impl FromStr for LogFormat {
+    type Err = String;
 
-            match command {
-                UciOut::Info {
-                    pv: None,
-                    string: None,
-                    score: None,
-                    ..
-                } => {
-                    // Skip noise.
-                    log::trace!("{} >> {}", session.0, command);
-                    continue;
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Direction {
+    In,
+    Out,
+}
+
+/// Logical state of an engine, as far as it can be observed from the
+/// commands that were sent and received.
+#[derive(Debug, Clone)]
+pub struct EngineState {
+    pub name: Option<String>,
+    pub options: HashMap<UciOptionName, Option<String>>,
+    pub position: Option<UciIn>,
+    pub searching: bool,
+}
+
+/// Identification of the engine, as far as it can be gathered from the
+/// `id` lines, the startup banner and informational output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineInfo {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    /// For example `15` or `dev-20220716-7ab5c6c0`, if the name ends with
+    /// a version.
+    pub version: Option<String>,
+    /// NNUE network file reported by the engine, if any.
+    pub nnue: Option<String>,
+    pub banner: Vec<String>,
+}
+
+/// Options advertised by an engine binary, as collected by
+/// [`Engine::probe()`].
+#[derive(Debug, Clone)]
+pub struct EngineProbe {
+    pub name: Option<String>,
+    pub options: HashMap<UciOptionName, UciOption>,
+}
+
+impl EngineProbe {
+    /// Checks initial options, as they would be passed to [`Engine::new()`].
+    /// Values out of range are reported even if the engine parameters would
+    /// clamp them.
+    pub fn validate_options(
+        &self,
+        options: &HashMap<UciOptionName, OptionOverride>,
+    ) -> Result<(), Vec<OptionError>> {
+        let mut errors = Vec::new();
+        for (name, value) in options {
+            let value = match value {
+                OptionOverride::Value(value) | OptionOverride::Required(value) => value,
+                OptionOverride::Default => continue,
+            };
+            match self.options.get(name) {
+                Some(option) => {
+                    if option.validate(Some(value.clone())).is_err() {
+                        errors.push(OptionError::InvalidValue {
+                            name: name.clone(),
+                            value: value.clone(),
+                        });
+                    }
                 }
-                UciOut::Info { .. } => log::debug!("{} >> {}", session.0, command),
-                _ => log::info!("{} >> {}", session.0, command),
+                None => errors.push(OptionError::Unknown(name.clone())),
             }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
 
-            match command {
-                UciOut::IdName(ref name) => self.name = Some(name.clone()),
-                UciOut::Uciok => self.pending_uciok = self.pending_uciok.saturating_sub(1),
-                UciOut::Readyok => self.pending_readyok = self.pending_readyok.saturating_sub(1),
-                UciOut::Bestmove { .. } => self.searching = false,
-                UciOut::Option {
-                    ref name,
-                    ref mut option,
-                } => {
-                    // Apply limits set in engine parameters.
-                    if *name == "Threads" {
-                        option.limit_max(self.params.max_threads.into());
-                    } else if *name == "Hash" {
-                        option.limit_max(self.params.max_hash.into());
-                    }
+/// Static evaluation of a position, as reported by Stockfish for `eval`.
+/// Centipawns from the point of view of white. Parts that the engine did
+/// not report, or could not evaluate, are `None`.
+#[cfg(feature = "stockfish")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaticEval {
+    pub classical: Option<i64>,
+    pub nnue: Option<i64>,
+    pub total: Option<i64>,
+}
+
+/// Result of the `bench` of Stockfish.
+#[cfg(feature = "stockfish")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchResult {
+    pub nodes: u64,
+    pub time: Duration,
+    pub nps: u64,
+}
+
+/// Resource options the engine advertises. Engines without `Threads` are
+/// single-threaded, engines without `Hash` use a fixed amount of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub threads: bool,
+    pub hash: bool,
+}
+
+/// The part of the protocol state that determines whether the engine is
+/// idle, as produced by [`Engine::replay()`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolState {
+    pub pending_uciok: u64,
+    pub pending_readyok: u64,
+    pub searching: bool,
+}
+
+/// Snapshot of the protocol state, for diagnosing an engine that does not
+/// become idle.
+#[derive(Debug, Clone)]
+pub struct EngineDebugState {
+    pub pending_uciok: u64,
+    pub pending_readyok: u64,
+    pub searching: bool,
+    /// Last line written to the engine.
+    pub last_sent: Option<String>,
+    /// Last line received from the engine, whether it was understood or
+    /// not.
+    pub last_received: Option<String>,
+}
+
+/// Snapshot of counters for monitoring.
+#[derive(Debug, Clone, Default)]
+pub struct EngineMetrics {
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    /// Number of searches that ended with `bestmove`.
+    pub searches: u64,
+    /// Total wallclock time from `go` to `bestmove` over all searches.
+    pub search_wallclock: Duration,
+}
+
+/// Summary of the principal variation of the current or most recent search.
+#[derive(Debug, Clone, Default)]
+pub struct SearchSummary {
+    pub depth: Option<u32>,
+    pub seldepth: Option<u32>,
+    pub time: Option<Duration>,
+    pub nodes: Option<u64>,
+    pub score: Option<Score>,
+    pub pv: Vec<Uci>,
+    pub bestmove: Option<Uci>,
+}
 
-                    self.options.insert(name.clone(), option.clone());
+impl SearchSummary {
+    fn update(&mut self, command: &UciOut) {
+        match command {
+            UciOut::Info {
+                multipv,
+                depth,
+                seldepth,
+                time,
+                nodes,
+                score,
+                pv,
+                ..
+            } if multipv.map_or(1, |n| n.get()) == 1 => {
+                // Fields may be reported independently of each other.
+                self.depth = depth.or(self.depth);
+                self.seldepth = seldepth.or(self.seldepth);
+                self.time = time.or(self.time);
+                self.nodes = nodes.or(self.nodes);
+                if let Some(score) = score {
+                    self.score = Some(score.clone());
+                }
+                if let Some(pv) = pv {
+                    self.pv = pv.clone();
                 }
-                _ => (),
             }
-
-            return Ok(command);
+            UciOut::Bestmove { m, .. } => self.bestmove = m.clone(),
+            _ => (),
         }
     }
+}
 
-    pub fn name(&self) -> Option<&str> {
-        self.name.as_deref()
+impl Engine {
+    pub async fn new(
+        path: PathBuf,
+        params: EngineParameters,
+        options: HashMap<UciOptionName, OptionOverride>,
+    ) -> io::Result<Engine> {
+        Engine::with_handshake_observer(path, params, options, |_| ()).await
     }
 
-    pub fn max_threads(&self) -> i64 {
-        self.options
-            .get(&UciOptionName("Threads".to_owned()))
-            .and_then(UciOption::max)
-            .unwrap_or(1)
+    /// Like [`Engine::new()`], but passes every line received during the
+    /// `uci` handshake to `observer`, as soon as it arrives.
+    pub async fn with_handshake_observer<F>(
+        path: PathBuf,
+        params: EngineParameters,
+        options: HashMap<UciOptionName, OptionOverride>,
+        observer: F,
+    ) -> io::Result<Engine>
+    where
+        F: FnMut(&UciOut),
+    {
+        let (child, stdin, stdout) = spawn_with_retry(&path, &params).await?;
+        Engine::from_io(path, Some(child), stdin, stdout, params, options, observer).await
     }
 
-    pub fn max_hash(&self) -> i64 {
-        self.options
-            .get(&UciOptionName("Hash".to_owned()))
-            .and_then(UciOption::max)
-            .unwrap_or(16)
+    /// Connects to an engine through `transport`, for example on a separate
+    /// machine, instead of spawning a local process. Everything else works
+    /// as with [`Engine::new()`], except that there is no process to
+    /// [`Engine::wait()`] for or to [`Engine::kill()`]. Restarting
+    /// reconnects.
+    pub async fn connect<T>(
+        transport: T,
+        params: EngineParameters,
+        options: HashMap<UciOptionName, OptionOverride>,
+    ) -> io::Result<Engine>
+    where
+        T: Transport + 'static,
+    {
+        let (stdin, stdout) = connect(&transport).await?;
+        let mut engine =
+            Engine::from_io(PathBuf::new(), None, stdin, stdout, params, options, |_| ()).await?;
+        engine.transport = Some(Box::new(transport));
+        Ok(engine)
     }
 
-    pub fn variants(&self) -> &[String] {
-        self.options
-            .get(&UciOptionName("UCI_Variant".to_owned()))
-            .and_then(UciOption::var)
-            .unwrap_or_default()
+    /// Connects to an engine that speaks UCI over a TCP stream at `addr`
+    /// (`host:port`). See [`Engine::connect()`].
+    pub async fn connect_tcp(
+        addr: String,
+        params: EngineParameters,
+        options: HashMap<UciOptionName, OptionOverride>,
+    ) -> io::Result<Engine> {
+        Engine::connect(TcpTransport { addr }, params, options).await
     }
 
-    pub fn is_searching(&self) -> bool {
-        self.searching
+    /// Runs only the `uci` handshake of the engine at `path` to collect its
+    /// options, and then asks it to quit. Limits and blocked options of
+    /// `params` apply as usual. Useful to check a configuration against the
+    /// actual binary, without starting a session.
+    pub async fn probe(path: PathBuf, params: EngineParameters) -> io::Result<EngineProbe> {
+        let stop = params.timeouts.stop.unwrap_or(QUIT_GRACE);
+        let mut engine = Engine::new(
+            path,
+            EngineParameters {
+                warmup: false,
+                ..params
+            },
+            HashMap::new(),
+        )
+        .await?;
+        let probe = EngineProbe {
+            name: engine.name.clone(),
+            options: engine.options.clone(),
+        };
+        let session = Session(0);
+        engine.quit(session).await?;
+        if engine.wait(session, Some(stop)).await.is_err() {
+            engine.kill(session).await?;
+        }
+        Ok(probe)
     }
 
-    pub fn is_idle(&self) -> bool {
-        self.pending_uciok == 0 && self.pending_readyok == 0 && !self.searching
+    /// Feeds `transcript` through the protocol state machine, without an
+    /// engine process. Commands are handled as if sent with
+    /// [`Engine::send_dangerous()`] and output as if received with
+    /// [`Engine::recv()`]. Returns the state after each entry.
+    ///
+    /// The transcript has to include the handshake. Timeouts do not apply.
+    /// Commands are replayed as recorded, so position transforms, move
+    /// truncation, `min_search_time`, `ready_before_go`, and
+    /// `sync_after_bestmove` are disabled.
+    pub async fn replay(
+        transcript: &Transcript,
+        params: EngineParameters,
+    ) -> io::Result<Vec<ProtocolState>> {
+        let mut engine = Engine::unstarted(
+            PathBuf::new(),
+            None,
+            BufWriter::new(Box::new(tokio::io::sink())),
+            BufReader::new(Box::new(tokio::io::empty())),
+            EngineParameters {
+                position_transform: None,
+                max_position_moves: None,
+                min_search_time: None,
+                ready_before_go: false,
+                sync_after_bestmove: false,
+                recorder: None,
+                ..params
+            },
+        );
+        let session = Session(0);
+        let mut states = Vec::with_capacity(transcript.entries.len());
+        for entry in &transcript.entries {
+            match entry.direction {
+                TranscriptDirection::In => {
+                    let command = UciIn::from_line(&entry.line)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "empty command")
+                        })?;
+                    engine.send_dangerous(session, command).await?;
+                }
+                TranscriptDirection::Out => {
+                    engine.handle_line(session, &entry.line, false)?;
+                }
+            }
+            states.push(engine.protocol_state());
+        }
+        Ok(states)
     }
 
-    pub async fn ensure_idle(&mut self, session: Session) -> io::Result<()> {
-        while !self.is_idle() {
-            if self.searching && self.pending_readyok < 1 {
-                self.send(session, UciIn::Stop).await?;
-                self.send(session, UciIn::Isready).await?;
-            }
-            self.recv(session).await?;
+    /// Starts an engine that is not backed by a real process. Commands are
+    /// validated and logged as usual, and answered immediately with canned
+    /// replies. Every search results in `bestmove 0000`, which is reported as
+    /// no move.
+    pub async fn dry_run(params: EngineParameters) -> io::Result<Engine> {
+        let (stdin, stdout) = fake_process(dry_run_reply);
+        Engine::from_io(
+            PathBuf::new(),
+            None,
+            stdin,
+            stdout,
+            params,
+            HashMap::new(),
+            |_| (),
+        )
+        .await
+    }
+
+    /// The engine state before the `uci` handshake.
+    fn unstarted(
+        path: PathBuf,
+        child: Option<Child>,
+        stdin: EngineStdin,
+        stdout: EngineStdout,
+        params: EngineParameters,
+    ) -> Engine {
+        Engine {
+            pending_uciok: 0,
+            pending_readyok: 0,
+            searching: false,
+            last_sent: None,
+            last_received: None,
+            partial_line: Vec::new(),
+            log_seq: 0,
+            options: HashMap::new(),
+            refreshed_options: None,
+            values: HashMap::new(),
+            session_values: HashMap::new(),
+            overlays: HashMap::new(),
+            overlay_base: HashMap::new(),
+            overlay_session: None,
+            position: None,
+            search_summary: SearchSummary::default(),
+            search_permit: None,
+            search_owner: None,
+            search_started: None,
+            search_generation: 0,
+            after_bestmove: false,
+            benchmarking: false,
+            stale_readyok: 0,
+            last_search_wallclock: None,
+            silent_stop_sent: false,
+            capped_stop_sent: false,
+            deferred_stop: false,
+            name: None,
+            author: None,
+            nnue: None,
+            banner: Vec::new(),
+            metrics: EngineMetrics::default(),
+            params,
+            command_filter: None,
+            output_tee: None,
+            tee_noise: false,
+            path,
+            transport: None,
+            exited: false,
+            stdin_closed: false,
+            crashes: VecDeque::new(),
+            quarantined_until: None,
+            draining: false,
+            last_activity: Instant::now(),
+            last_memory_check: Instant::now(),
+            child,
+            stdin,
+            stdout,
         }
-        Ok(())
     }
 
-    pub async fn ensure_newgame(&mut self, session: Session) -> io::Result<()> {
-        self.ensure_idle(session).await?;
-        self.send(session, UciIn::Ucinewgame).await?;
-        self.send(session, UciIn::Isready).await?;
-        self.ensure_idle(session).await?;
+    async fn from_io<F>(
+        path: PathBuf,
+        child: Option<Child>,
+        stdin: EngineStdin,
+        stdout: EngineStdout,
+        params: EngineParameters,
+        options: HashMap<UciOptionName, OptionOverride>,
+        observer: F,
+    ) -> io::Result<Engine>
+    where
+        F: FnMut(&UciOut),
+    {
+        let mut engine = Engine::unstarted(path, child, stdin, stdout, params);
+
+        let session = Session(0);
+        engine.send(session, UciIn::Uci).await?;
+        engine.ensure_idle_with(session, observer).await?;
+        let mut wdl_configured = false;
+        for (name, value) in options {
+            let value = match value {
+                OptionOverride::Value(value) => value,
+                OptionOverride::Default => {
+                    log::debug!("{}: leaving option at default: {}", session.0, name);
+                    continue;
+                }
+                OptionOverride::Required(_) if !engine.options.contains_key(&name) => {
+                    log::error!("{}: required option not supported: {}", session.0, name);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        EngineError::UnknownOption(name),
+                    ));
+                }
+                OptionOverride::Required(value) => value,
+            };
+            if name == "UCI_ShowWDL" {
+                wdl_configured = true;
+            }
+            engine
+                .send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name,
+                        value: Some(value),
+                    },
+                )
+                .await?;
+        }
+        if engine.params.kind == EngineKind::NeuralNet && !wdl_configured {
+            engine.enable_wdl(session).await?;
+        }
+        for name in SESSION_OPTIONS {
+            let name = UciOptionName(name.to_owned());
+            if engine.options.contains_key(&name) {
+                let value = engine.current_value(&name);
+                engine.session_values.insert(name, value);
+            }
+        }
+        if engine.params.warmup {
+            engine.warm_up(session).await?;
+        }
+        engine.log_capabilities();
+        Ok(engine)
+    }
+
+    async fn warm_up(&mut self, session: Session) -> io::Result<()> {
+        log::info!("{}: warming up engine ...", session.0);
+        let started = Instant::now();
+        self.analyze(
+            session,
+            UciIn::Position {
+                fen: None,
+                moves: Vec::new(),
+            },
+            go_command(Some(1), None),
+            true,
+        )
+        .await?;
+        self.ensure_idle(session).await?;
+        // The warmup is not part of any session.
+        self.position = None;
+        self.search_summary = SearchSummary::default();
+        log::info!("{}: warmed up in {:?}", session.0, started.elapsed());
+        Ok(())
+    }
+
+    /// Spawns a new process for the same engine, and restores the options
+    /// and position of the previous one.
+    pub async fn restart(&mut self, session: Session) -> io::Result<()> {
+        self.check_quarantine(session)?;
+        let state = self.engine_state();
+        self.quit_previous(session).await;
+        let (child, stdin, stdout) = match self.transport {
+            Some(ref transport) => {
+                let (stdin, stdout) = connect(transport.as_ref()).await?;
+                (None, stdin, stdout)
+            }
+            None => {
+                let (child, stdin, stdout) = spawn_with_retry(&self.path, &self.params).await?;
+                (Some(child), stdin, stdout)
+            }
+        };
+        self.attach(session, state, child, stdin, stdout).await
+    }
+
+    fn check_quarantine(&mut self, session: Session) -> io::Result<()> {
+        if let BreakerState::Open { retry_in } = self.breaker_state() {
+            log::error!("{}: not restarting quarantined engine", session.0);
+            return Err(io::Error::other(EngineError::Quarantined(retry_in)));
+        }
+        self.quarantined_until = None;
+        Ok(())
+    }
+
+    async fn quit_previous(&mut self, session: Session) {
+        if !self.exited {
+            // Best effort. The previous process will also see its stdin
+            // closed.
+            let _ = self.quit(session).await;
+        }
+    }
+
+    /// Takes over a freshly started process, and restores `state` on it.
+    async fn attach(
+        &mut self,
+        session: Session,
+        state: EngineState,
+        child: Option<Child>,
+        stdin: EngineStdin,
+        stdout: EngineStdout,
+    ) -> io::Result<()> {
+        self.child = child;
+        self.stdin = stdin;
+        self.stdout = stdout;
+        self.partial_line.clear();
+        self.banner.clear();
+        self.nnue.take();
+        self.reset_protocol_state();
+        self.exited = false;
+        self.stdin_closed = false;
+        self.last_activity = Instant::now();
+        self.last_memory_check = Instant::now();
+
+        self.send(session, UciIn::Uci).await?;
+        self.ensure_idle(session).await?;
+        self.restore_state(session, state).await
+    }
+
+    /// Replaces the engine process with one running a different binary,
+    /// restoring options and position like [`Engine::restart()`]. The new
+    /// binary is started before the previous process is quit, so that it
+    /// keeps running if the new binary cannot be started at all. If the new
+    /// engine fails the handshake, it is killed, and the next restart uses
+    /// the previous binary (or transport) again. An engine reached through a
+    /// [`Transport`] is replaced by the local process.
+    pub async fn swap_binary(&mut self, session: Session, path: PathBuf) -> io::Result<()> {
+        self.check_quarantine(session)?;
+        log::warn!(
+            "{}: swapping engine {:?} for {:?}",
+            session.0,
+            self.path,
+            path
+        );
+        let (child, stdin, stdout) = spawn_with_retry(&path, &self.params).await?;
+        let state = self.engine_state();
+        self.quit_previous(session).await;
+        let previous_path = mem::replace(&mut self.path, path);
+        let previous_transport = self.transport.take();
+        if let Err(err) = self
+            .attach(session, state, Some(child), stdin, stdout)
+            .await
+        {
+            log::error!(
+                "{}: swapped engine failed, reverting to {:?}: {}",
+                session.0,
+                previous_path,
+                err
+            );
+            let _ = self.kill(session).await;
+            self.path = previous_path;
+            self.transport = previous_transport;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Asks the engine process to exit.
+    pub async fn quit(&mut self, session: Session) -> io::Result<()> {
+        self.send_dangerous(session, UciIn::Quit).await
+    }
+
+    /// Closes the input of the engine process, without killing it. Most
+    /// engines exit on their own when they see the end of input, even in the
+    /// middle of a search. After this, sending commands fails. Use
+    /// [`Engine::wait()`] to wait for the process to exit.
+    pub async fn close_stdin(&mut self, session: Session) -> io::Result<()> {
+        log::warn!("{}: closing engine stdin", session.0);
+        let _ = self.stdin.flush().await;
+        // Dropping the writer closes the pipe.
+        self.stdin = BufWriter::new(Box::new(tokio::io::sink()));
+        self.stdin_closed = true;
+        Ok(())
+    }
+
+    /// Waits for the engine process to exit, for example after
+    /// [`Engine::close_stdin()`], and returns its exit status. Fails with
+    /// [`io::ErrorKind::TimedOut`] if it does not exit within `timeout`.
+    pub async fn wait(
+        &mut self,
+        session: Session,
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<ExitStatus>> {
+        let status = match self.child {
+            Some(ref mut child) => Some(match timeout {
+                Some(timeout) => {
+                    tokio::time::timeout(timeout, child.wait())
+                        .await
+                        .map_err(|_| {
+                            io::Error::new(io::ErrorKind::TimedOut, "engine did not exit")
+                        })??
+                }
+                None => child.wait().await?,
+            }),
+            None => None,
+        };
+        log::info!("{}: engine exited with {:?}", session.0, status);
+        self.exited = true;
+        self.reset_protocol_state();
+        Ok(status)
+    }
+
+    /// Kills the engine process immediately, without giving it a chance to
+    /// shut down gracefully.
+    pub async fn kill(&mut self, session: Session) -> io::Result<()> {
+        log::warn!("{}: killing engine", session.0);
+        self.exited = true;
+        self.reset_protocol_state();
+        match self.child {
+            Some(ref mut child) => child.kill().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a neural network engine may still be loading its network,
+    /// because it has not completed a search yet.
+    fn loading_network(&self) -> bool {
+        self.params.kind == EngineKind::NeuralNet && self.metrics.searches == 0
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        match self.quarantined_until {
+            Some(until) if until > Instant::now() => BreakerState::Open {
+                retry_in: until - Instant::now(),
+            },
+            _ => BreakerState::Closed {
+                recent_crashes: self.crashes.len(),
+            },
+        }
+    }
+
+    /// Records an unexpected exit, and opens the crash breaker if there
+    /// were too many recently.
+    fn record_crash(&mut self, session: Session) {
+        let Some(ref breaker) = self.params.crash_breaker else {
+            return;
+        };
+        let now = Instant::now();
+        while self
+            .crashes
+            .front()
+            .is_some_and(|crash| now.duration_since(*crash) > breaker.window)
+        {
+            self.crashes.pop_front();
+        }
+        self.crashes.push_back(now);
+        if self.crashes.len() >= breaker.max_crashes {
+            log::error!(
+                "{}: engine crashed {} times within {:?}, quarantining for {:?}",
+                session.0,
+                self.crashes.len(),
+                breaker.window,
+                breaker.cooldown
+            );
+            self.crashes.clear();
+            self.quarantined_until = Some(now + breaker.cooldown);
+        }
+    }
+
+    /// Sends `stop` the first time the engine goes silent during a search,
+    /// and gives up if it stays silent after that.
+    async fn handle_silence(&mut self, session: Session) -> io::Result<()> {
+        if self.silent_stop_sent {
+            log::error!("{}: engine still silent after stop", session.0);
+            Err(io::Error::new(io::ErrorKind::TimedOut, EngineError::Silent))
+        } else {
+            log::warn!("{}: engine silent during search, sending stop", session.0);
+            self.silent_stop_sent = true;
+            self.write_command(session, &UciIn::Stop).await
+        }
+    }
+
+    /// Gives back the permit of a search that failed to start or to stop,
+    /// so that it does not count against [`EngineParameters::search_limit`]
+    /// forever.
+    fn release_search_permit(&mut self, session: Session) {
+        if self.search_permit.take().is_some() {
+            log::warn!("{}: released search permit of failed search", session.0);
+        }
+    }
+
+    /// Forgets about pending replies and searches, for when the process is
+    /// gone.
+    fn reset_protocol_state(&mut self) {
+        self.pending_uciok = 0;
+        self.pending_readyok = 0;
+        self.refreshed_options = None;
+        self.searching = false;
+        self.search_permit = None;
+        self.search_owner = None;
+        self.search_started = None;
+        self.after_bestmove = false;
+        self.benchmarking = false;
+        self.stale_readyok = 0;
+    }
+
+    pub async fn send(&mut self, session: Session, command: UciIn) -> io::Result<()> {
+        match command {
+            UciIn::Setoption { ref name, .. }
+                if !name.is_safe()
+                    && self.params.unknown_option_policy == UnknownOptionPolicy::Error
+                    && !self.options.contains_key(name) =>
+            {
+                log::error!("{}: unknown option: {}", session.0, command);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    EngineError::UnknownOption(name.clone()),
+                ))
+            }
+            UciIn::Setoption { ref name, .. } if !name.is_safe() => {
+                log::error!(
+                    "{}: rejected potentially unsafe option: {}",
+                    session.0,
+                    command
+                );
+                Ok(())
+            }
+            UciIn::Quit => {
+                log::error!("{}: rejected quit", session.0);
+                Ok(())
+            }
+            UciIn::Go { perft: Some(_), .. } => {
+                // Not limited by max_nodes, max_search_time or stop.
+                log::error!("{}: rejected perft: {}", session.0, command);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "perft not allowed",
+                ))
+            }
+            UciIn::Position { .. } | UciIn::Go { .. }
+                if self.searching
+                    && self.params.auto_stop_on_go
+                    && self.search_owner.unwrap_or(session) == session =>
+            {
+                log::debug!("{}: stopping previous search for: {}", session.0, command);
+                self.send_dangerous(session, UciIn::Stop).await?;
+                let started = Instant::now();
+                while self.searching {
+                    let timeout = self
+                        .params
+                        .timeouts
+                        .stop
+                        .map(|t| t.saturating_sub(started.elapsed()));
+                    self.recv_timeout(session, timeout).await?;
+                }
+                self.send_dangerous(session, command).await
+            }
+            UciIn::Stop if self.search_owner.is_some_and(|owner| owner != session) => {
+                log::warn!(
+                    "{}: ignoring stop for search of session {}",
+                    session.0,
+                    self.search_owner.map_or(0, |owner| owner.0)
+                );
+                Ok(())
+            }
+            _ => self.send_dangerous(session, command).await,
+        }
+    }
+
+    /// Installs a filter that sees every command before it is validated and
+    /// written to the engine, including commands sent internally. It can
+    /// rewrite the command in place, or deny it. Replaces any previous
+    /// filter. Without a filter, all commands are allowed.
+    pub fn set_command_filter<F>(&mut self, filter: F)
+    where
+        F: FnMut(&mut UciIn) -> Decision + Send + 'static,
+    {
+        self.command_filter = Some(Box::new(filter));
+    }
+
+    /// Installs a sink that receives a copy of every command parsed from the
+    /// engine output, before principal variations are truncated. With
+    /// `include_noise`, it also receives `info` lines without analysis,
+    /// that are otherwise skipped. Replaces any previous sink.
+    pub fn set_output_tee<F>(&mut self, sink: F, include_noise: bool)
+    where
+        F: FnMut(UciOut) + Send + 'static,
+    {
+        self.output_tee = Some(Box::new(sink));
+        self.tee_noise = include_noise;
+    }
+
+    pub async fn send_dangerous(&mut self, session: Session, mut command: UciIn) -> io::Result<()> {
+        if self.exited {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                EngineError::EngineExited,
+            ));
+        }
+        if self.stdin_closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "engine stdin closed",
+            ));
+        }
+
+        if let Some(ref mut filter) = self.command_filter {
+            if filter(&mut command) == Decision::Deny {
+                log::warn!("{}: command filter denied: {}", session.0, command);
+                return Ok(());
+            }
+        }
+
+        let mut refresh_options = false;
+        match command {
+            UciIn::Isready if self.pending_readyok >= MAX_PENDING_READYOK => {
+                log::warn!("{}: collapsing redundant isready", session.0);
+                return Ok(());
+            }
+            UciIn::Isready => self.pending_readyok += 1,
+            UciIn::Stop if !self.searching && !self.params.always_send_stop => {
+                log::debug!("{}: not searching, dropping stop", session.0);
+                return Ok(());
+            }
+            UciIn::Stop
+                if self.searching
+                    && self
+                        .params
+                        .min_search_time
+                        .zip(self.search_started)
+                        .is_some_and(|(t, started)| started.elapsed() < t) =>
+            {
+                log::debug!("{}: deferring stop to minimum search time", session.0);
+                self.deferred_stop = true;
+                return Ok(());
+            }
+            UciIn::Stop | UciIn::Ponderhit => (),
+            UciIn::Quit => {
+                self.exited = true;
+                self.reset_protocol_state();
+            }
+            _ if self.searching => {
+                log::error!("{}: engine is busy: {}", session.0, command);
+                return Err(io::Error::other("engine is busy"));
+            }
+            UciIn::Uci if self.pending_uciok > 0 => {
+                log::warn!("{}: collapsing redundant uci", session.0);
+                return Ok(());
+            }
+            UciIn::Uci => {
+                self.pending_uciok = 1;
+                self.refreshed_options = None;
+                self.options.clear();
+                self.name.take();
+                self.author.take();
+            }
+            #[cfg(feature = "stockfish")]
+            UciIn::Bench => self.benchmarking = true,
+            UciIn::Ucinewgame if !self.params.supports_ucinewgame => {
+                log::debug!("{}: engine does not support ucinewgame", session.0);
+                return Ok(());
+            }
+            UciIn::Go {
+                ref mut nodes,
+                ref mut movetime,
+                ..
+            } => {
+                if let (Some(n), Some(max_nodes)) = (*nodes, self.params.max_nodes) {
+                    if n > max_nodes {
+                        log::warn!("{}: clamping nodes {} to {}", session.0, n, max_nodes);
+                        *nodes = Some(max_nodes);
+                    }
+                }
+                if let (Some(t), Some(max_search_time)) = (*movetime, self.params.max_search_time) {
+                    if t > max_search_time {
+                        log::warn!(
+                            "{}: clamping movetime {:?} to {:?}",
+                            session.0,
+                            t,
+                            max_search_time
+                        );
+                        *movetime = Some(max_search_time);
+                    }
+                }
+                if self.draining {
+                    log::error!("{}: rejected go while draining", session.0);
+                    return Err(io::Error::other(EngineError::Draining));
+                }
+                if self.params.ready_before_go {
+                    // Also consumes any output of the previous search.
+                    self.pending_readyok += 1;
+                    self.write_command(session, &UciIn::Isready).await?;
+                    let started = Instant::now();
+                    while self.pending_readyok > 0 {
+                        let timeout = self.params.timeouts.isready;
+                        self.recv_timeout(
+                            session,
+                            timeout.map(|t| t.saturating_sub(started.elapsed())),
+                        )
+                        .await?;
+                    }
+                    self.after_bestmove = false;
+                }
+                if let Some(ref limit) = self.params.search_limit {
+                    let permits = Arc::clone(&limit.permits);
+                    self.search_permit = Some(match limit.policy {
+                        SearchLimitPolicy::Wait => permits
+                            .acquire_owned()
+                            .await
+                            .map_err(|_| io::Error::other(EngineError::TooManySearches))?,
+                        SearchLimitPolicy::Reject => permits.try_acquire_owned().map_err(|_| {
+                            log::error!("{}: too many concurrent searches", session.0);
+                            io::Error::other(EngineError::TooManySearches)
+                        })?,
+                    });
+                }
+                if mem::take(&mut self.after_bestmove) && self.params.sync_after_bestmove {
+                    // Some engines print info after bestmove. Everything
+                    // before the readyok still belongs to the previous
+                    // search.
+                    self.pending_readyok += 1;
+                    self.stale_readyok = self.pending_readyok;
+                    if let Err(err) = self.write_command(session, &UciIn::Isready).await {
+                        self.release_search_permit(session);
+                        return Err(err);
+                    }
+                }
+                // Before writing, so that even an instant bestmove is
+                // attributed to this search.
+                self.search_generation += 1;
+                self.searching = true;
+                self.search_owner = Some(session);
+                self.search_started = Some(Instant::now());
+                self.silent_stop_sent = false;
+                self.capped_stop_sent = false;
+                self.deferred_stop = false;
+                self.search_summary = SearchSummary::default();
+            }
+            UciIn::Position { ref fen, ref moves } => {
+                self.position = Some(command.clone());
+                if let Some(ref transform) = self.params.position_transform {
+                    let pos = self.setup_position(fen, moves).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "cannot transform position")
+                    })?;
+                    command = UciIn::Position {
+                        fen: Some(Fen::from_position(
+                            (transform.position)(pos),
+                            EnPassantMode::Legal,
+                        )),
+                        moves: Vec::new(),
+                    };
+                } else if let Some(max_moves) = self
+                    .params
+                    .max_position_moves
+                    .filter(|max_moves| moves.len() > *max_moves)
+                {
+                    let split = moves.len() - max_moves;
+                    match self.setup_position(fen, &moves[..split]) {
+                        Some(pos) => {
+                            log::debug!(
+                                "{}: playing {} of {} moves into the fen",
+                                session.0,
+                                split,
+                                moves.len()
+                            );
+                            command = UciIn::Position {
+                                fen: Some(Fen::from_position(pos, EnPassantMode::Legal)),
+                                moves: moves[split..].to_vec(),
+                            };
+                        }
+                        None => log::warn!(
+                            "{}: cannot truncate moves, sending all {}",
+                            session.0,
+                            moves.len()
+                        ),
+                    }
+                }
+            }
+            UciIn::Setoption {
+                ref name,
+                ref mut value,
+            } => match self.options.get(name) {
+                Some(option) => {
+                    refresh_options = *name == "UCI_Variant" && self.current_value(name) != *value;
+                    if let (OnOutOfRange::Clamp, Some(Ok(n))) = (
+                        self.params.on_out_of_range,
+                        value.as_deref().map(str::parse::<i64>),
+                    ) {
+                        let clamped = option.clamp(n);
+                        if clamped != n {
+                            log::warn!(
+                                "{}: clamping option {} from {} to {}",
+                                session.0,
+                                name,
+                                n,
+                                clamped
+                            );
+                            *value = Some(clamped.to_string());
+                        }
+                    }
+                    option
+                        .validate(value.clone())
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    if *option != UciOption::Button {
+                        self.values.insert(name.clone(), value.clone());
+                    }
+                }
+                None if name.0 == "Threads" || name.0 == "Hash" => {
+                    log::debug!(
+                        "{}: ignoring {}, engine does not support {}",
+                        session.0,
+                        command,
+                        name
+                    );
+                    return Ok(());
+                }
+                None => match self.params.unknown_option_policy {
+                    UnknownOptionPolicy::Ignore => {
+                        log::warn!("{}: ignoring unknown option: {}", session.0, command);
+                        return Ok(());
+                    }
+                    UnknownOptionPolicy::Error => {
+                        log::error!("{}: unknown option: {}", session.0, command);
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            EngineError::UnknownOption(name.clone()),
+                        ));
+                    }
+                },
+            },
+            _ => (),
+        }
+
+        if let Err(err) = self.write_command(session, &command).await {
+            if matches!(command, UciIn::Go { .. }) {
+                // The search never started.
+                self.searching = false;
+                self.search_owner = None;
+                self.search_started = None;
+                self.release_search_permit(session);
+            }
+            return Err(err);
+        }
+        if refresh_options {
+            self.refresh_options(session).await?;
+        }
+        Ok(())
+    }
+
+    /// Has the engine advertise its options again, because they depend on
+    /// the variant, and waits for `uciok`. The output is consumed here, so
+    /// that it does not reach the client. Options that are not advertised
+    /// again are forgotten.
+    async fn refresh_options(&mut self, session: Session) -> io::Result<()> {
+        log::debug!("{}: refreshing options", session.0);
+        self.pending_uciok = 1;
+        self.refreshed_options = Some(HashSet::new());
+        self.write_command(session, &UciIn::Uci).await?;
+        let started = Instant::now();
+        while self.pending_uciok > 0 {
+            let timeout = self.params.timeouts.handshake;
+            self.recv_timeout(
+                session,
+                timeout.map(|t| t.saturating_sub(started.elapsed())),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn write_command(&mut self, session: Session, command: &UciIn) -> io::Result<()> {
+        let mut buf = command.to_string();
+        self.log_line(Level::Info, session, Direction::In, &buf);
+        if let Some(ref recorder) = self.params.recorder {
+            // Including commands the engine layer sends on its own, like
+            // the isready of ready_before_go.
+            recorder.record(TranscriptDirection::In, &buf);
+        }
+        self.last_sent = Some(buf.clone());
+        buf.push_str("\r\n");
+        self.last_activity = Instant::now();
+        let write = async {
+            self.stdin.write_all(buf.as_bytes()).await?;
+            self.stdin.flush().await
+        };
+        match self.params.timeouts.write {
+            Some(timeout) => match tokio::time::timeout(timeout, write).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    log::error!("{}: engine not reading input", session.0);
+                    // The command may have been written partially, so the
+                    // input is unusable.
+                    self.stdin = BufWriter::new(Box::new(tokio::io::sink()));
+                    self.kill(session).await?;
+                    self.record_crash(session);
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "engine not reading input",
+                    ));
+                }
+            },
+            None => write.await?,
+        }
+        self.metrics.bytes_written += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Receives the next line of engine output. Output is parsed in
+    /// [`crate::uci::ParseMode::Lenient`], so that nonstandard extensions do
+    /// not break the session.
+    pub async fn recv(&mut self, session: Session) -> io::Result<UciOut> {
+        self.recv_with(session, false).await
+    }
+
+    /// Like [`Engine::recv()`], but optionally skips `info` lines without
+    /// parsing them.
+    async fn recv_with(&mut self, session: Session, skip_info: bool) -> io::Result<UciOut> {
+        loop {
+            if self.exited {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    EngineError::EngineExited,
+                ));
+            }
+
+            loop {
+                let silent_deadline = self
+                    .params
+                    .timeouts
+                    .silent
+                    .filter(|_| self.searching && !self.loading_network())
+                    .map(|t| tokio::time::Instant::from_std(self.last_activity + t));
+                let capped_deadline = self
+                    .params
+                    .max_search_time
+                    .zip(self.search_started)
+                    .filter(|_| self.searching && !self.capped_stop_sent)
+                    .map(|(t, started)| tokio::time::Instant::from_std(started + t));
+                let deferred_deadline = self
+                    .params
+                    .min_search_time
+                    .zip(self.search_started)
+                    .filter(|_| self.searching && self.deferred_stop)
+                    .map(|(t, started)| tokio::time::Instant::from_std(started + t));
+                let memory_deadline = self
+                    .params
+                    .memory_guard
+                    .as_ref()
+                    .filter(|_| self.child.is_some())
+                    .map(|guard| {
+                        tokio::time::Instant::from_std(self.last_memory_check + guard.interval)
+                    });
+                if memory_deadline.is_some_and(|deadline| deadline <= tokio::time::Instant::now()) {
+                    self.check_memory(session).await?;
+                    continue;
+                }
+                let deadline = [
+                    silent_deadline,
+                    capped_deadline,
+                    deferred_deadline,
+                    memory_deadline,
+                ]
+                .into_iter()
+                .flatten()
+                .min();
+                match deadline {
+                    Some(deadline) => {
+                        match timeout_at(
+                            deadline,
+                            self.stdout.read_until(b'\n', &mut self.partial_line),
+                        )
+                        .await
+                        {
+                            Ok(res) => {
+                                res?;
+                                break;
+                            }
+                            Err(_) if capped_deadline == Some(deadline) => {
+                                log::warn!(
+                                    "{}: search exceeded {:?}, sending stop",
+                                    session.0,
+                                    self.params.max_search_time.unwrap_or_default()
+                                );
+                                self.capped_stop_sent = true;
+                                self.deferred_stop = false;
+                                self.write_command(session, &UciIn::Stop).await?;
+                            }
+                            Err(_) if deferred_deadline == Some(deadline) => {
+                                log::debug!("{}: sending deferred stop", session.0);
+                                self.deferred_stop = false;
+                                self.write_command(session, &UciIn::Stop).await?;
+                            }
+                            Err(_) if memory_deadline == Some(deadline) => {
+                                self.check_memory(session).await?;
+                            }
+                            Err(_) => self.handle_silence(session).await?,
+                        }
+                    }
+                    None => {
+                        self.stdout
+                            .read_until(b'\n', &mut self.partial_line)
+                            .await?;
+                        break;
+                    }
+                }
+            }
+            // Partial reads may have been interrupted by the watchdog, or by
+            // cancelling an earlier receive.
+            let line = String::from_utf8(mem::take(&mut self.partial_line))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let n = line.len();
+            self.metrics.bytes_read += n as u64;
+            if n == 0 {
+                self.exited = true;
+                if !self.stdin_closed {
+                    self.record_crash(session);
+                }
+                let searching = self.searching;
+                self.reset_protocol_state();
+                if searching && self.params.synthetic_bestmove_on_exit {
+                    log::error!("{}: engine exited during search", session.0);
+                    return Ok(UciOut::Bestmove {
+                        m: None,
+                        ponder: None,
+                    });
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    EngineError::EngineExited,
+                ));
+            }
+            self.last_activity = Instant::now();
+            if self.params.log_raw_lines {
+                self.log_line(Level::Trace, session, Direction::Out, &line.escape_debug());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            self.last_received = Some(line.to_owned());
+            if let Some(ref recorder) = self.params.recorder {
+                recorder.record(TranscriptDirection::Out, line);
+            }
+
+            if let Some(command) = self.handle_line(session, line, skip_info)? {
+                return Ok(command);
+            }
+        }
+    }
+
+    /// Processes a line of engine output. Returns `None` for lines that are
+    /// not passed on, like noise or output that is not UCI.
+    fn handle_line(
+        &mut self,
+        session: Session,
+        line: &str,
+        skip_info: bool,
+    ) -> io::Result<Option<UciOut>> {
+        if skip_info
+            && line
+                .strip_prefix("info")
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+        {
+            return Ok(None);
+        }
+
+        let mut command = match UciOut::from_line(line) {
+            Err(err) => {
+                self.log_line(Level::Error, session, Direction::Out, &line);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            Ok(None) if self.pending_uciok > 0 => {
+                // Many engines print a banner before the handshake.
+                self.log_line(Level::Debug, session, Direction::Out, &line);
+                if self.banner.len() < MAX_BANNER_LINES {
+                    self.banner.push(line.to_owned());
+                }
+                if self.name.is_none()
+                    && self.options.is_empty()
+                    && self.banner.len() >= MAX_NON_UCI_LINES
+                {
+                    log::error!("{}: engine does not speak uci", session.0);
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        EngineError::NotUci(self.banner.clone()),
+                    ));
+                }
+                return Ok(None);
+            }
+            Ok(None) => {
+                self.log_line(Level::Warn, session, Direction::Out, &line);
+                return Ok(None);
+            }
+            Ok(Some(command)) => command,
+        };
+
+        // Only search output. Other info, like info string, also comes
+        // while idle, for example after setoption EvalFile.
+        let search_output = matches!(
+            command,
+            UciOut::Info {
+                ref depth,
+                ref score,
+                ref currmove,
+                ref pv,
+                ..
+            } if depth.is_some() || score.is_some() || currmove.is_some() || pv.is_some()
+        );
+        if search_output && (self.after_bestmove || self.stale_readyok > 0) && !self.benchmarking {
+            log::debug!(
+                "{}: discarding info of search {} after bestmove: {}",
+                session.0,
+                self.search_generation - u64::from(self.stale_readyok > 0),
+                line
+            );
+            return Ok(None);
+        }
+
+        if let Some(ref transform) = self.params.position_transform {
+            match command {
+                UciOut::Bestmove {
+                    ref mut m,
+                    ref mut ponder,
+                } => {
+                    *m = m.take().map(&transform.uci);
+                    *ponder = ponder.take().map(&transform.uci);
+                }
+                UciOut::Info {
+                    ref mut currmove,
+                    ref mut pv,
+                    ..
+                } => {
+                    *currmove = currmove.take().map(&transform.uci);
+                    if let Some(pv) = pv {
+                        for m in pv {
+                            *m = (transform.uci)(m.clone());
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        self.search_summary.update(&command);
+
+        let noise = matches!(
+            command,
+            UciOut::Info {
+                pv: None,
+                string: None,
+                score: None,
+                ..
+            }
+        );
+        if let Some(ref mut tee) = self.output_tee {
+            if !noise || self.tee_noise {
+                tee(command.clone());
+            }
+        }
+
+        if let (UciOut::Info { pv: Some(pv), .. }, Some(max_pv_length)) =
+            (&mut command, self.params.max_pv_length)
+        {
+            pv.truncate(max_pv_length);
+        }
+
+        match command {
+            _ if noise => {
+                // Skip noise.
+                self.log_line(Level::Trace, session, Direction::Out, &command);
+                return Ok(None);
+            }
+            UciOut::Info { .. } => self.log_line(Level::Debug, session, Direction::Out, &command),
+            UciOut::Option { .. } if self.params.quiet_options => {
+                self.log_line(Level::Debug, session, Direction::Out, &command)
+            }
+            _ => self.log_line(Level::Info, session, Direction::Out, &command),
+        }
+
+        match command {
+            UciOut::IdName(ref name) => self.name = Some(name.clone()),
+            UciOut::IdAuthor(ref author) => self.author = Some(author.clone()),
+            UciOut::Info {
+                string: Some(ref string),
+                ..
+            } if string.starts_with("NNUE") => {
+                if let Some(net) = nnue_net(string) {
+                    self.nnue = Some(net.to_owned());
+                }
+            }
+            UciOut::Uciok => {
+                self.pending_uciok = self.pending_uciok.saturating_sub(1);
+                if let Some(refreshed) = self.refreshed_options.take() {
+                    self.options.retain(|name, _| refreshed.contains(name));
+                }
+                if self.params.quiet_options {
+                    log::info!(
+                        "{}: engine advertised {} options",
+                        session.0,
+                        self.options.len()
+                    );
+                }
+            }
+            UciOut::Readyok => {
+                self.pending_readyok = self.pending_readyok.saturating_sub(1);
+                if self.stale_readyok > 0 {
+                    self.stale_readyok -= 1;
+                    if self.stale_readyok == 0 {
+                        // Reply to the isready sent along with go.
+                        return Ok(None);
+                    }
+                }
+            }
+            UciOut::Bestmove { .. } | UciOut::Perft { .. } => {
+                self.after_bestmove = true;
+                self.searching = false;
+                self.search_permit = None;
+                self.search_owner = None;
+                if let Some(started) = self.search_started.take() {
+                    let wallclock = started.elapsed();
+                    self.last_search_wallclock = Some(wallclock);
+                    self.metrics.searches += 1;
+                    self.metrics.search_wallclock += wallclock;
+                }
+            }
+            UciOut::Option { ref name, .. } if self.params.blocked_options.contains(name) => {
+                log::debug!("{}: hiding blocked option: {}", session.0, name);
+                return Ok(None);
+            }
+            UciOut::Option {
+                ref name,
+                ref mut option,
+            } => {
+                // Apply limits set in engine parameters.
+                if *name == "Threads" {
+                    option.limit_max(self.params.max_threads.into());
+                } else if *name == "Hash" {
+                    option.limit_max(self.hash_limit());
+                }
+
+                if let Some(ref mut refreshed) = self.refreshed_options {
+                    refreshed.insert(name.clone());
+                }
+                self.options.insert(name.clone(), option.clone());
+            }
+            _ => (),
+        }
+
+        #[cfg(feature = "validation")]
+        if let UciOut::Bestmove { m: Some(ref m), .. } = command {
+            if self.is_legal_bestmove(m) == Some(false) {
+                log::error!("{}: illegal bestmove: {}", session.0, m);
+                if self.params.reject_illegal_bestmove {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        EngineError::IllegalBestmove(m.clone()),
+                    ));
+                }
+            }
+        }
+
+        Ok(Some(command))
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Unrecognized lines printed by the engine during the `uci` handshake,
+    /// usually including version information.
+    pub fn banner(&self) -> &[String] {
+        &self.banner
+    }
+
+    pub fn engine_info(&self) -> EngineInfo {
+        EngineInfo {
+            name: self.name.clone(),
+            author: self.author.clone(),
+            version: self.name.as_deref().and_then(|name| {
+                name.rsplit(' ')
+                    .next()
+                    .filter(|version| {
+                        name != *version && version.contains(|c: char| c.is_ascii_digit())
+                    })
+                    .map(str::to_owned)
+            }),
+            nnue: self.nnue.clone().or_else(|| {
+                self.banner
+                    .iter()
+                    .find_map(|line| nnue_net(line))
+                    .map(str::to_owned)
+            }),
+            banner: self.banner.clone(),
+        }
+    }
+
+    pub fn options(&self) -> &HashMap<UciOptionName, UciOption> {
+        &self.options
+    }
+
+    pub fn engine_state(&self) -> EngineState {
+        EngineState {
+            name: self.name.clone(),
+            options: self.values.clone(),
+            position: self.position.clone(),
+            searching: self.searching,
+        }
+    }
+
+    /// Replays the options and position of a previously captured state.
+    /// An in-flight search is not resumed.
+    pub async fn restore_state(&mut self, session: Session, state: EngineState) -> io::Result<()> {
+        self.ensure_idle(session).await?;
+        if state.name.is_some() && state.name != self.name {
+            log::warn!(
+                "{}: restoring state of {:?} onto {:?}",
+                session.0,
+                state.name,
+                self.name
+            );
+        }
+        for (name, value) in state.options {
+            self.send_dangerous(session, UciIn::Setoption { name, value })
+                .await?;
+        }
+        if let Some(position) = state.position {
+            self.send_dangerous(session, position).await?;
+        }
+        Ok(())
+    }
+
+    /// Value of an option, as last set successfully, or else the default
+    /// advertised by the engine.
+    pub fn current_value(&self, name: &UciOptionName) -> Option<String> {
+        match self.values.get(name) {
+            Some(value) => value.clone(),
+            None => self
+                .options
+                .get(name)
+                .and_then(|option| option.descriptor(name).default),
+        }
+    }
+
+    pub fn current_threads(&self) -> Option<i64> {
+        self.current_value(&UciOptionName("Threads".to_owned()))
+            .and_then(|value| value.parse().ok())
+    }
+
+    pub fn current_hash(&self) -> Option<i64> {
+        self.current_value(&UciOptionName("Hash".to_owned()))
+            .and_then(|value| value.parse().ok())
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            threads: self
+                .options
+                .contains_key(&UciOptionName("Threads".to_owned())),
+            hash: self.options.contains_key(&UciOptionName("Hash".to_owned())),
+        }
+    }
+
+    /// Maximum `Threads` value, or 1 if the engine is single-threaded.
+    pub fn max_threads(&self) -> i64 {
+        self.options
+            .get(&UciOptionName("Threads".to_owned()))
+            .and_then(UciOption::max)
+            .unwrap_or(1)
+    }
+
+    /// Limit for the hash table size (MiB), from the engine parameters.
+    fn hash_limit(&self) -> i64 {
+        let limit = i64::from(self.params.max_hash);
+        match self.params.max_hash_fraction {
+            Some(fraction) => {
+                let sys = System::new_with_specifics(RefreshKind::new().with_memory());
+                let total = (sys.total_memory() / 1024) as f64;
+                min(limit, (total * fraction) as i64)
+            }
+            None => limit,
+        }
+    }
+
+    /// Maximum `Hash` value, or the size of the engine's fixed table
+    /// (assumed to be 16 MiB) if it has no `Hash` option.
+    pub fn max_hash(&self) -> i64 {
+        self.options
+            .get(&UciOptionName("Hash".to_owned()))
+            .and_then(UciOption::max)
+            .unwrap_or(16)
+    }
+
+    pub fn variants(&self) -> &[String] {
+        self.options
+            .get(&UciOptionName("UCI_Variant".to_owned()))
+            .and_then(UciOption::var)
+            .unwrap_or_default()
+    }
+
+    /// Like [`Engine::variants()`], but lowercased, sorted and without
+    /// duplicates or entries that are not playable games.
+    pub fn normalized_variants(&self) -> Vec<String> {
+        let mut variants: Vec<String> = self
+            .variants()
+            .iter()
+            .map(|variant| variant.trim().to_ascii_lowercase())
+            .filter(|variant| !variant.is_empty() && !NON_GAME_VARIANTS.contains(&variant.as_str()))
+            .collect();
+        variants.sort_unstable();
+        variants.dedup();
+        variants
+    }
+
+    /// Switches to `variant` for the session, comparing names like
+    /// [`Engine::normalized_variants()`]. Fails early with
+    /// [`EngineError::UnsupportedVariant`] if the engine does not offer it,
+    /// instead of analyzing with the wrong rules. Engines without
+    /// `UCI_Variant` only support `chess`.
+    pub async fn select_variant(&mut self, session: Session, variant: &str) -> io::Result<()> {
+        let normalized = variant.trim().to_ascii_lowercase();
+        let selected = self
+            .variants()
+            .iter()
+            .find(|v| v.trim().to_ascii_lowercase() == normalized)
+            .filter(|_| !NON_GAME_VARIANTS.contains(&normalized.as_str()))
+            .cloned();
+        match selected {
+            Some(selected) => self.set_option(session, "UCI_Variant", &selected).await,
+            None if normalized == "chess" && !self.has_option("UCI_Variant") => Ok(()),
+            None => {
+                log::error!("{}: variant not supported: {}", session.0, variant);
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    EngineError::UnsupportedVariant(variant.to_owned()),
+                ))
+            }
+        }
+    }
+
+    /// Whether [`Engine::set_deterministic()`] can make the engine search
+    /// single-threaded.
+    pub fn supports_determinism(&self) -> bool {
+        match self.options.get(&UciOptionName("Threads".to_owned())) {
+            Some(option) => option.min().is_some_and(|min| min <= 1),
+            None => true,
+        }
+    }
+
+    /// Configures advertised options for reproducible analysis: a single
+    /// search thread, no pondering and a fixed hash size.
+    ///
+    /// Results are only reproducible with the same engine version and
+    /// configuration, starting from a new game. They can still differ across
+    /// engine versions.
+    pub async fn set_deterministic(&mut self, session: Session) -> io::Result<()> {
+        if self.has_option("Threads") {
+            self.set_option(session, "Threads", "1").await?;
+        }
+        if self.has_option("Ponder") {
+            self.set_option(session, "Ponder", "false").await?;
+        }
+        if let Some(hash) = self.options.get(&UciOptionName("Hash".to_owned())) {
+            let value = hash.clamp(DETERMINISTIC_HASH);
+            self.set_option(session, "Hash", &value.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the engine can report win/draw/loss statistics with its
+    /// scores, see [`Engine::enable_wdl()`].
+    pub fn supports_wdl(&self) -> bool {
+        self.has_option("UCI_ShowWDL")
+    }
+
+    /// Asks the engine to report win/draw/loss statistics with its scores,
+    /// if supported. Does nothing if they are already enabled. Like other
+    /// session options, `UCI_ShowWDL` is reset at the start of the next
+    /// session. Returns whether the engine supports it.
+    pub async fn enable_wdl(&mut self, session: Session) -> io::Result<bool> {
+        if !self.supports_wdl() {
+            return Ok(false);
+        }
+        let name = UciOptionName("UCI_ShowWDL".to_owned());
+        if self.current_value(&name).as_deref() != Some("true") {
+            // Not in the list of safe options, so that clients cannot set
+            // it directly.
+            self.send_dangerous(
+                session,
+                UciIn::Setoption {
+                    name,
+                    value: Some("true".to_owned()),
+                },
+            )
+            .await?;
+        }
+        Ok(true)
+    }
+
+    /// Clears the transposition table, using the `Clear Hash` button if the
+    /// engine advertises it, or `ucinewgame` otherwise.
+    pub async fn clear_hash(&mut self, session: Session) -> io::Result<()> {
+        if self.has_option("Clear Hash") {
+            self.send(
+                session,
+                UciIn::Setoption {
+                    name: UciOptionName("Clear Hash".to_owned()),
+                    value: None,
+                },
+            )
+            .await
+        } else {
+            self.send(session, UciIn::Ucinewgame).await
+        }
+    }
+
+    /// Sets an option and waits until the engine has processed it, by
+    /// following up with `isready`. UCI has no acknowledgment for
+    /// `setoption`, but engines handle commands in order, so a `go` sent
+    /// afterwards does not race, for example, a slow hash reallocation.
+    pub async fn set_option_sync(
+        &mut self,
+        session: Session,
+        name: UciOptionName,
+        value: Option<String>,
+    ) -> io::Result<()> {
+        self.send(session, UciIn::Setoption { name, value }).await?;
+        self.send(session, UciIn::Isready).await?;
+        self.ensure_idle(session).await
+    }
+
+    /// Changes `Threads` and `Hash` between searches. Stops any search
+    /// first, clamps the values to the limits, and returns only once the
+    /// engine has answered `isready`, so that reallocating the hash table
+    /// has completed. Options the engine does not advertise are skipped.
+    pub async fn reconfigure(
+        &mut self,
+        session: Session,
+        threads: Option<i64>,
+        hash: Option<i64>,
+    ) -> io::Result<()> {
+        self.ensure_idle(session).await?;
+        for (name, value) in [("Threads", threads), ("Hash", hash)] {
+            let (Some(value), Some(option)) =
+                (value, self.options.get(&UciOptionName(name.to_owned())))
+            else {
+                continue;
+            };
+            let value = option.clamp(value);
+            self.set_option(session, name, &value.to_string()).await?;
+        }
+        self.send(session, UciIn::Isready).await?;
+        self.ensure_idle(session).await
+    }
+
+    /// Checks `m` against the last position sent, or `None` if that is not
+    /// possible, for example in variants.
+    #[cfg(feature = "validation")]
+    fn is_legal_bestmove(&self, m: &Uci) -> Option<bool> {
+        let pos = match self.position {
+            Some(UciIn::Position { ref fen, ref moves }) => self.setup_position(fen, moves)?,
+            _ => return None,
+        };
+        Some(m.to_move(&pos).is_ok())
+    }
+
+    /// Replays `moves` from `fen`, or `None` if that is not possible, for
+    /// example in variants.
+    fn setup_position(&self, fen: &Option<Fen>, moves: &[Uci]) -> Option<Chess> {
+        let variant = self.current_value(&UciOptionName("UCI_Variant".to_owned()));
+        if variant.is_some_and(|variant| variant != "chess") {
+            return None;
+        }
+        let mode = CastlingMode::from_chess960(
+            self.current_value(&UciOptionName("UCI_Chess960".to_owned()))
+                .is_some_and(|value| value == "true"),
+        );
+        let mut pos: Chess = match fen {
+            Some(fen) => fen.clone().into_position(mode).ok()?,
+            None => Chess::default(),
+        };
+        for uci in moves {
+            let m = uci.to_move(&pos).ok()?;
+            pos.play_unchecked(&m);
+        }
+        Some(pos)
+    }
+
+    fn log_line(
+        &mut self,
+        level: Level,
+        session: Session,
+        direction: Direction,
+        line: &dyn fmt::Display,
+    ) {
+        self.log_seq += 1;
+        match self.params.log_format {
+            LogFormat::Plain => {
+                let arrow = match direction {
+                    Direction::In => "<<",
+                    Direction::Out => ">>",
+                };
+                log::log!(level, "{} {} {}", session.0, arrow, line);
+            }
+            LogFormat::Json => log::log!(
+                level,
+                "{}",
+                serde_json::json!({
+                    "session": session.0,
+                    "dir": match direction {
+                        Direction::In => "in",
+                        Direction::Out => "out",
+                    },
+                    "line": line.to_string(),
+                    "ts": SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |ts| ts.as_millis() as u64),
+                    "seq": self.log_seq,
+                })
+            ),
+        }
+    }
+
+    /// Logs a single summary of what the engine supports, with the
+    /// configured limits applied.
+    pub fn log_capabilities(&self) {
+        let name = self.name.as_deref().unwrap_or("unknown engine");
+        let author = self.author.as_deref().unwrap_or("unknown author");
+        let max_multipv = self
+            .options
+            .get(&UciOptionName("MultiPV".to_owned()))
+            .and_then(UciOption::max)
+            .unwrap_or(1);
+        let chess960 = self.has_option("UCI_Chess960");
+        let variants = self.normalized_variants();
+        match self.params.log_format {
+            LogFormat::Plain => log::info!(
+                "engine {} by {}: max threads {}, max hash {} MiB, variants [{}], max multipv {}, chess960 {}",
+                name,
+                author,
+                self.max_threads(),
+                self.max_hash(),
+                variants.join(", "),
+                max_multipv,
+                chess960
+            ),
+            LogFormat::Json => log::info!(
+                "{}",
+                serde_json::json!({
+                    "event": "capabilities",
+                    "name": name,
+                    "author": author,
+                    "max_threads": self.max_threads(),
+                    "max_hash": self.max_hash(),
+                    "variants": variants,
+                    "max_multipv": max_multipv,
+                    "chess960": chess960,
+                    "ts": SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |ts| ts.as_millis() as u64),
+                })
+            ),
+        }
+    }
+
+    fn has_option(&self, name: &str) -> bool {
+        self.options.contains_key(&UciOptionName(name.to_owned()))
+    }
+
+    async fn set_option(&mut self, session: Session, name: &str, value: &str) -> io::Result<()> {
+        self.send(
+            session,
+            UciIn::Setoption {
+                name: UciOptionName(name.to_owned()),
+                value: Some(value.to_owned()),
+            },
+        )
+        .await
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.metrics.bytes_written
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.metrics.bytes_read
+    }
+
+    pub fn protocol_state(&self) -> ProtocolState {
+        ProtocolState {
+            pending_uciok: self.pending_uciok,
+            pending_readyok: self.pending_readyok,
+            searching: self.searching,
+        }
+    }
+
+    pub fn debug_state(&self) -> EngineDebugState {
+        EngineDebugState {
+            pending_uciok: self.pending_uciok,
+            pending_readyok: self.pending_readyok,
+            searching: self.searching,
+            last_sent: self.last_sent.clone(),
+            last_received: self.last_received.clone(),
+        }
+    }
+
+    pub fn metrics(&self) -> EngineMetrics {
+        self.metrics.clone()
+    }
+
+    /// Wallclock time from sending `go` to receiving `bestmove` for the most
+    /// recent search, as measured by this process. Unlike the `time` reported
+    /// by the engine, this includes all overhead, like piping output.
+    pub fn last_search_wallclock(&self) -> Option<Duration> {
+        self.last_search_wallclock
+    }
+
+    pub fn last_search_summary(&self) -> &SearchSummary {
+        &self.search_summary
+    }
+
+    /// First move of the principal variation most recently reported in the
+    /// current search, for cheaply polling whether the engine agrees with a
+    /// move. Reset by `go`.
+    pub fn current_best_first_move(&self) -> Option<Uci> {
+        self.search_summary.pv.first().cloned()
+    }
+
+    pub fn is_exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Process ID of the engine, for attaching external supervision like
+    /// cgroups. `None` once the engine has exited (or has been asked to
+    /// quit), or if it is not backed by a process.
+    pub fn pid(&self) -> Option<u32> {
+        self.child
+            .as_ref()
+            .and_then(Child::id)
+            .filter(|_| !self.exited)
+    }
+
+    /// Resident memory of the engine process in bytes, as reported by
+    /// `/proc/<pid>/statm`. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn resident_memory(&self) -> Option<u64> {
+        let statm = std::fs::read_to_string(format!("/proc/{}/statm", self.pid()?)).ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        // SAFETY: sysconf has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * u64::try_from(page_size).ok()?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn resident_memory(&self) -> Option<u64> {
+        None
+    }
+
+    /// Enforces the [`MemoryGuard`].
+    async fn check_memory(&mut self, session: Session) -> io::Result<()> {
+        self.last_memory_check = Instant::now();
+        let (Some(max_resident), Some(resident)) = (
+            self.params
+                .memory_guard
+                .as_ref()
+                .map(|guard| guard.max_resident),
+            self.resident_memory(),
+        ) else {
+            return Ok(());
+        };
+        if resident <= max_resident {
+            return Ok(());
+        }
+        log::error!(
+            "{}: engine uses {} MiB of memory, more than {} MiB, quitting",
+            session.0,
+            resident / (1024 * 1024),
+            max_resident / (1024 * 1024)
+        );
+        let _ = self.write_command(session, &UciIn::Quit).await;
+        let grace = self.params.timeouts.stop.unwrap_or(QUIT_GRACE);
+        if self.wait(session, Some(grace)).await.is_err() {
+            self.kill(session).await?;
+        }
+        self.record_crash(session);
+        Err(io::Error::other(EngineError::OutOfMemory(resident)))
+    }
+
+    /// Time since the last command was sent to or received from the engine.
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Stops accepting new searches. A search in progress is allowed to
+    /// finish, and `stop` and `isready` are still accepted. Any further `go`
+    /// fails with [`EngineError::Draining`].
+    pub fn drain(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Whether draining was requested and no search is in progress anymore.
+    pub fn is_drained(&self) -> bool {
+        self.draining && !self.searching
+    }
+
+    /// Waits until the search in progress, if any, has finished on its own.
+    /// Call [`Engine::drain()`] first, so that no new search can start.
+    pub async fn drained(&mut self, session: Session) -> io::Result<()> {
+        while self.searching {
+            self.recv(session).await?;
+        }
+        Ok(())
+    }
+
+    /// The session that started the current search, if any. Only that
+    /// session can stop it with [`Engine::send()`].
+    pub fn search_owner(&self) -> Option<Session> {
+        self.search_owner
+    }
+
+    /// Number of searches started so far. Output is attributed to the
+    /// search of this generation, or discarded if it arrives after its
+    /// `bestmove`.
+    pub fn search_generation(&self) -> u64 {
+        self.search_generation
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.searching
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending_uciok == 0 && self.pending_readyok == 0 && !self.searching
+    }
+
+    /// Whether a new position and `go` can be sent right now: the engine is
+    /// running, not searching, and no `uciok` or `readyok` is pending.
+    ///
+    /// Apart from the exit check, this is currently the same as
+    /// [`Engine::is_idle()`]. But `is_idle` is about whether the engine has
+    /// caught up with all commands, and may diverge once pondering is
+    /// tracked separately.
+    pub fn can_accept_search(&self) -> bool {
+        !self.exited && !self.searching && self.pending_uciok == 0 && self.pending_readyok == 0
+    }
+
+    pub async fn ensure_idle(&mut self, session: Session) -> io::Result<()> {
+        self.ensure_idle_with(session, |_| ()).await
+    }
+
+    /// Like [`Engine::ensure_idle()`], but passes every line received in the
+    /// meantime to `observer`.
+    pub async fn ensure_idle_with<F>(&mut self, session: Session, mut observer: F) -> io::Result<()>
+    where
+        F: FnMut(&UciOut),
+    {
+        let started = Instant::now();
+        while !self.is_idle() {
+            if self.searching && self.pending_readyok < 1 {
+                self.send_dangerous(session, UciIn::Stop).await?;
+                self.send(session, UciIn::Isready).await?;
+            }
+            let timeout = if self.pending_uciok > 0 {
+                self.params.timeouts.handshake
+            } else if self.searching {
+                self.params.timeouts.stop
+            } else {
+                self.params.timeouts.isready
+            };
+            let command = match self
+                .recv_timeout(
+                    session,
+                    timeout.map(|t| t.saturating_sub(started.elapsed())),
+                )
+                .await
+            {
+                Ok(command) => command,
+                Err(err) => {
+                    self.release_search_permit(session);
+                    return Err(err);
+                }
+            };
+            observer(&command);
+        }
+        Ok(())
+    }
+
+    /// Searches `position` with the limits of `go`, passing every `info` to
+    /// `predicate`, and stops the search as soon as the predicate returns
+    /// `true`. Returns the best move, if any.
+    pub async fn analyze_until<P>(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        go: UciIn,
+        mut predicate: P,
+    ) -> io::Result<Option<Uci>>
+    where
+        P: FnMut(&UciOut) -> bool,
+    {
+        if !matches!(position, UciIn::Position { .. }) || !matches!(go, UciIn::Go { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected position and go",
+            ));
+        }
+
+        self.ensure_idle(session).await?;
+        self.send(session, position).await?;
+        self.send(session, go).await?;
+
+        let mut stopped = false;
+        loop {
+            match self.recv(session).await? {
+                UciOut::Bestmove { m, .. } => return Ok(m),
+                info @ UciOut::Info { .. } if !stopped && predicate(&info) => {
+                    self.send(session, UciIn::Stop).await?;
+                    stopped = true;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Searches `position` with the limits of `go`, and returns the best
+    /// move, if any. With `bestmove_only`, `info` lines are skipped without
+    /// even parsing them, which saves work in batch analysis, but leaves
+    /// [`Engine::last_search_summary()`] empty.
+    pub async fn analyze(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        go: UciIn,
+        bestmove_only: bool,
+    ) -> io::Result<Option<Uci>> {
+        if !matches!(position, UciIn::Position { .. }) || !matches!(go, UciIn::Go { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected position and go",
+            ));
+        }
+
+        self.ensure_idle(session).await?;
+        self.send(session, position).await?;
+        self.send(session, go).await?;
+
+        loop {
+            if let UciOut::Bestmove { m, .. } = self.recv_with(session, bestmove_only).await? {
+                return Ok(m);
+            }
+        }
+    }
+
+    /// Asks Stockfish for the static evaluation of `position`, with the
+    /// nonstandard `eval`. Engines that do not understand `eval` result in
+    /// an empty [`StaticEval`].
+    #[cfg(feature = "stockfish")]
+    pub async fn static_eval(
+        &mut self,
+        session: Session,
+        position: UciIn,
+    ) -> io::Result<StaticEval> {
+        if !matches!(position, UciIn::Position { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected position",
+            ));
+        }
+
+        self.ensure_idle(session).await?;
+        self.send(session, position).await?;
+        self.send(session, UciIn::Eval).await?;
+        // The breakdown has no definite end.
+        self.send(session, UciIn::Isready).await?;
+
+        let mut eval = StaticEval::default();
+        let started = Instant::now();
+        while !self.is_idle() {
+            let timeout = self.params.timeouts.isready;
+            if let UciOut::Eval { kind, cp } = self
+                .recv_timeout(
+                    session,
+                    timeout.map(|t| t.saturating_sub(started.elapsed())),
+                )
+                .await?
+            {
+                match kind {
+                    EvalKind::Classical => eval.classical = cp,
+                    EvalKind::Nnue => eval.nnue = cp,
+                    EvalKind::Final => eval.total = cp,
+                }
+            }
+        }
+        Ok(eval)
+    }
+
+    /// Runs the nonstandard `bench` of Stockfish, for comparing engine
+    /// builds and hardware. It can take a while, so `timeout` is for the
+    /// whole benchmark. Stockfish prints the summary to stderr. Unless the
+    /// engine prints it to standard output, the nodes of the individual
+    /// searches are added up, and the time is measured here, including
+    /// overhead.
+    #[cfg(feature = "stockfish")]
+    pub async fn bench(
+        &mut self,
+        session: Session,
+        timeout: Option<Duration>,
+    ) -> io::Result<BenchResult> {
+        self.ensure_idle(session).await?;
+        self.send(session, UciIn::Bench).await?;
+        // The output has no definite end.
+        self.send(session, UciIn::Isready).await?;
+
+        let started = Instant::now();
+        let (mut nodes, mut time, mut nps) = (None, None, None);
+        let mut searched = 0;
+        let mut last_nodes = None;
+        let res = async {
+            while !self.is_idle() {
+                match self
+                    .recv_timeout(
+                        session,
+                        timeout.map(|t| t.saturating_sub(started.elapsed())),
+                    )
+                    .await?
+                {
+                    UciOut::Info { nodes: Some(n), .. } => last_nodes = Some(n),
+                    UciOut::Bestmove { .. } => searched += last_nodes.take().unwrap_or(0),
+                    UciOut::Bench { stat, value } => match stat {
+                        BenchStat::TotalTime => time = Some(Duration::from_millis(value)),
+                        BenchStat::Nodes => nodes = Some(value),
+                        BenchStat::Nps => nps = Some(value),
+                    },
+                    _ => (),
+                }
+            }
+            io::Result::Ok(())
+        }
+        .await;
+        self.benchmarking = false;
+        self.after_bestmove = false;
+        res?;
+
+        let nodes = nodes.unwrap_or(searched);
+        let time = time.unwrap_or_else(|| started.elapsed());
+        Ok(BenchResult {
+            nodes,
+            time,
+            nps: nps.unwrap_or_else(|| (u128::from(nodes) * 1000 / time.as_millis().max(1)) as u64),
+        })
+    }
+
+    /// Counts the leaf nodes of the move tree of `position` to `depth`, with
+    /// the nonstandard `go perft`. Useful to validate move generation, for
+    /// example in variants. Clients cannot send `go perft` themselves, and
+    /// depths above 7 are rejected, because perft cannot be stopped.
+    pub async fn perft(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        depth: u32,
+    ) -> io::Result<u64> {
+        if !matches!(position, UciIn::Position { .. }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expected position",
+            ));
+        }
+        if depth > MAX_PERFT_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("perft depth {depth} exceeds {MAX_PERFT_DEPTH}"),
+            ));
+        }
+
+        self.ensure_idle(session).await?;
+        self.send(session, position).await?;
+        self.send_dangerous(
+            session,
+            UciIn::Go {
+                searchmoves: None,
+                ponder: false,
+                wtime: None,
+                btime: None,
+                winc: None,
+                binc: None,
+                movestogo: None,
+                depth: None,
+                nodes: None,
+                mate: None,
+                movetime: None,
+                infinite: false,
+                perft: Some(depth),
+            },
+        )
+        .await?;
+
+        loop {
+            match self.recv(session).await? {
+                UciOut::Perft { nodes } => return Ok(nodes),
+                UciOut::Bestmove { .. } => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "engine does not support perft",
+                    ))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Builds the `go` command for `request`, clamped to the limits in the
+    /// engine parameters. Fails for empty limits, like a depth of 0. Times
+    /// are rounded up to whole milliseconds, because `movetime 0` means no
+    /// limit to some engines.
+    pub fn go(&self, session: Session, request: AnalysisRequest) -> io::Result<UciIn> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "empty search limit");
+        let (mut movetime, mut depth, mut nodes, mut mate, mut infinite) =
+            (None, None, None, None, false);
+        match request {
+            AnalysisRequest::ForTime(t) if t.is_zero() => return Err(invalid()),
+            AnalysisRequest::ForTime(t) => movetime = Some(t.max(Duration::from_millis(1))),
+            AnalysisRequest::ToDepth(0) => return Err(invalid()),
+            AnalysisRequest::ToDepth(d) => depth = Some(d),
+            AnalysisRequest::ForNodes(0) => return Err(invalid()),
+            AnalysisRequest::ForNodes(n) => {
+                nodes = Some(match self.params.max_nodes {
+                    Some(max_nodes) if n > max_nodes => {
+                        log::warn!("{}: clamping nodes {} to {}", session.0, n, max_nodes);
+                        max_nodes
+                    }
+                    _ => n,
+                })
+            }
+            AnalysisRequest::Infinite => infinite = true,
+            AnalysisRequest::Mate(0) => return Err(invalid()),
+            AnalysisRequest::Mate(m) => mate = Some(m),
+        }
+        Ok(UciIn::Go {
+            searchmoves: None,
+            ponder: false,
+            wtime: None,
+            btime: None,
+            winc: None,
+            binc: None,
+            movestogo: None,
+            depth,
+            nodes,
+            mate,
+            movetime,
+            infinite,
+            perft: None,
+        })
+    }
+
+    /// Like [`Engine::analyze()`], with the search limits of `request`. An
+    /// [`AnalysisRequest::Infinite`] search would never finish on its own,
+    /// so it is rejected here. Send it with [`Engine::send()`] instead.
+    pub async fn analyze_request(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        request: AnalysisRequest,
+        bestmove_only: bool,
+    ) -> io::Result<Option<Uci>> {
+        if request == AnalysisRequest::Infinite {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "infinite analysis does not finish",
+            ));
+        }
+        let go = self.go(session, request)?;
+        self.analyze(session, position, go, bestmove_only).await
+    }
+
+    /// Switches to the options of `profile`. Fails without changing anything
+    /// if the engine does not advertise one of the options. Only options
+    /// that actually change are sent.
+    pub async fn apply_profile(&mut self, session: Session, profile: &Profile) -> io::Result<()> {
+        let options = profile.options();
+        if let Some((name, _)) = options
+            .iter()
+            .find(|(name, _)| !self.options.contains_key(name))
+        {
+            log::error!(
+                "{}: profile {} uses unsupported option: {}",
+                session.0,
+                profile.name,
+                name
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                EngineError::UnknownOption(name.clone()),
+            ));
+        }
+        log::info!("{}: applying profile {}", session.0, profile.name);
+        for (name, value) in options {
+            if self.current_value(&name).as_deref() != Some(value.as_str()) {
+                // Profiles are configured by the operator, so they may set
+                // options that clients cannot.
+                self.send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name,
+                        value: Some(value),
+                    },
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether sessions may set `name` for themselves, see
+    /// [`EngineParameters::overlay_options`].
+    pub fn is_overlay_option(&self, name: &UciOptionName) -> bool {
+        self.params.overlay_options.contains(name)
+    }
+
+    /// Sets an option only for `session`, without changing the configuration
+    /// seen by other sessions. Overlays are applied by
+    /// [`Engine::activate_session()`], or immediately if `session` is
+    /// already active. Fails with [`EngineError::NotOverlayOption`] unless
+    /// the option is one of [`EngineParameters::overlay_options`], and with
+    /// [`io::ErrorKind::InvalidData`] if the engine would reject the value.
+    pub async fn set_session_option(
+        &mut self,
+        session: Session,
+        name: UciOptionName,
+        value: Option<String>,
+    ) -> io::Result<()> {
+        if !self.is_overlay_option(&name) {
+            log::error!("{}: rejected session option: {}", session.0, name);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                EngineError::NotOverlayOption(name),
+            ));
+        }
+        if let Some(option) = self.options.get(&name) {
+            option
+                .validate(value.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+        self.overlays
+            .entry(session)
+            .or_default()
+            .insert(name, value);
+        if self.overlay_session == Some(session) {
+            self.activate_session(session).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the option overlay of `session`. The options are reverted at the
+    /// next [`Engine::activate_session()`].
+    pub fn forget_session(&mut self, session: Session) {
+        self.overlays.remove(&session);
+    }
+
+    /// Applies the option overlay of `session`, reverting any options of the
+    /// previously active overlay. Only options that actually change are
+    /// sent to the engine.
+    pub async fn activate_session(&mut self, session: Session) -> io::Result<()> {
+        let overlay = self.overlays.get(&session).cloned().unwrap_or_default();
+        let mut names: Vec<_> = self.overlay_base.keys().cloned().collect();
+        names.extend(
+            overlay
+                .keys()
+                .filter(|name| !self.overlay_base.contains_key(name))
+                .cloned(),
+        );
+        for name in names {
+            let current = self.current_value(&name);
+            let target = match overlay.get(&name) {
+                Some(value) => {
+                    self.overlay_base
+                        .entry(name.clone())
+                        .or_insert_with(|| current.clone());
+                    value.clone()
+                }
+                None => self.overlay_base.remove(&name).flatten(),
+            };
+            if current != target {
+                // Only overlay options get here, and reverting restores a
+                // value that was set before.
+                self.send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name,
+                        value: target,
+                    },
+                )
+                .await?;
+            }
+        }
+        self.overlay_session = Some(session);
+        Ok(())
+    }
+
+    /// Thinks about `position` for `movetime` and returns the best move, or
+    /// `None` in terminal positions. Starts a new game first, unless the
+    /// position continues the previous one.
+    pub async fn best_move(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        movetime: Duration,
+    ) -> io::Result<Option<Uci>> {
+        let continues = match (&self.position, &position) {
+            (
+                Some(UciIn::Position {
+                    fen: prev_fen,
+                    moves: prev_moves,
+                }),
+                UciIn::Position { fen, moves },
+            ) => prev_fen == fen && moves.starts_with(prev_moves),
+            _ => false,
+        };
+        if !continues {
+            self.ensure_newgame(session).await?;
+        }
+        self.analyze(session, position, go_command(None, Some(movetime)), true)
+            .await
+    }
+
+    /// Forwards engine output to `tx` until the current search ends with
+    /// `bestmove`, applying `backpressure` when the channel is full.
+    pub async fn forward(
+        &mut self,
+        session: Session,
+        tx: &mpsc::Sender<UciOut>,
+        backpressure: Backpressure,
+    ) -> io::Result<()> {
+        let mut dropped = 0;
+        while self.searching {
+            let command = self.recv(session).await?;
+            let consumer_gone = || io::Error::new(io::ErrorKind::BrokenPipe, "consumer gone");
+            match (backpressure, &command) {
+                (Backpressure::DropInfo, UciOut::Info { .. }) => match tx.try_send(command) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) => dropped += 1,
+                    Err(TrySendError::Closed(_)) => return Err(consumer_gone()),
+                },
+                _ => tx.send(command).await.map_err(|_| consumer_gone())?,
+            }
+        }
+        if dropped > 0 {
+            log::debug!(
+                "{}: dropped {} info lines for slow consumer",
+                session.0,
+                dropped
+            );
+        }
+        Ok(())
+    }
+
+    /// Stops the current search, if any, and waits for its `bestmove`.
+    pub async fn ensure_stopped(&mut self, session: Session) -> io::Result<()> {
+        let started = Instant::now();
+        if self.searching {
+            self.send_dangerous(session, UciIn::Stop).await?;
+        }
+        while self.searching {
+            let timeout = self.params.timeouts.stop;
+            if let Err(err) = self
+                .recv_timeout(
+                    session,
+                    timeout.map(|t| t.saturating_sub(started.elapsed())),
+                )
+                .await
+            {
+                self.release_search_permit(session);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv_timeout(
+        &mut self,
+        session: Session,
+        timeout: Option<Duration>,
+    ) -> io::Result<UciOut> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.recv(session))
+                .await
+                .map_err(|_| {
+                    log::error!("{}: engine timed out", session.0);
+                    io::Error::new(io::ErrorKind::TimedOut, "engine timed out")
+                })?,
+            None => self.recv(session).await,
+        }
+    }
+
+    /// Prepares the engine for a new game or session. Session-scoped
+    /// options, like `MultiPV`, are reset to their initial values, unless
+    /// the session sets them again afterwards.
+    pub async fn ensure_newgame(&mut self, session: Session) -> io::Result<()> {
+        self.ensure_idle(session).await?;
+        let changed: Vec<_> = self
+            .session_values
+            .iter()
+            .filter(|(name, value)| self.current_value(name) != **value)
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        for (name, value) in changed {
+            log::debug!("{}: resetting session option: {}", session.0, name);
+            // Restores an initial value, even for options that clients
+            // cannot set themselves.
+            self.send_dangerous(session, UciIn::Setoption { name, value })
+                .await?;
+        }
+        if !self.params.supports_ucinewgame {
+            return Ok(());
+        }
+        self.send(session, UciIn::Ucinewgame).await?;
+        self.send(session, UciIn::Isready).await?;
+        self.ensure_idle(session).await?;
+        Ok(())
+    }
+}
+
+fn spawn(
+    path: &Path,
+    args: &[String],
+    sandbox: Option<&[String]>,
+    rlimits: Option<&RLimits>,
+) -> io::Result<(Child, EngineStdin, EngineStdout)> {
+    let mut command = match sandbox {
+        Some([wrapper, wrapper_args @ ..]) => {
+            log::info!("Starting engine {path:?} {args:?} in sandbox {sandbox:?} ...");
+            let mut command = Command::new(wrapper);
+            command.args(wrapper_args).arg(path);
+            command
+        }
+        _ => {
+            log::info!("Starting engine {path:?} {args:?} ...");
+            Command::new(path)
+        }
+    };
+    command
+        .args(args)
+        .stdout(Stdio::piped())
+        .stdin(Stdio::piped());
+    if let Some(rlimits) = rlimits {
+        rlimits.apply(&mut command);
+    }
+    let mut process = command.spawn()?;
+
+    let stdin = process
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "engine stdin closed"))?;
+    let stdout = process
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "engine stdout closed"))?;
+
+    Ok((
+        process,
+        BufWriter::new(Box::new(stdin)),
+        BufReader::new(Box::new(stdout)),
+    ))
+}
+
+async fn spawn_with_retry(
+    path: &Path,
+    params: &EngineParameters,
+) -> io::Result<(Child, EngineStdin, EngineStdout)> {
+    let mut retries = 0;
+    loop {
+        match spawn(
+            path,
+            &params.args,
+            params.sandbox.as_deref(),
+            params.rlimits.as_ref(),
+        ) {
+            Err(err) => match params.spawn_retry {
+                Some(ref retry)
+                    if retries < retry.attempts
+                        && err
+                            .raw_os_error()
+                            .is_some_and(|errno| retry.errnos.contains(&errno)) =>
+                {
+                    let backoff = retry.backoff * 2u32.saturating_pow(retries);
+                    retries += 1;
+                    log::warn!(
+                        "Failed to start engine ({}), retry {}/{} in {:?}",
+                        err,
+                        retries,
+                        retry.attempts,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                _ => return Err(err),
+            },
+            res => return res,
+        }
+    }
+}
+
+async fn connect(transport: &dyn Transport) -> io::Result<(EngineStdin, EngineStdout)> {
+    log::info!("Connecting to engine at {transport:?} ...");
+
+    let (write, read) = transport.connect().await?;
+
+    Ok((BufWriter::new(write), BufReader::new(read)))
+}
+
+/// Runs a task that pretends to be an engine process, answering each command
+/// with the lines returned by `respond`.
+fn fake_process<F>(mut respond: F) -> (EngineStdin, EngineStdout)
+where
+    F: FnMut(&str) -> Vec<String> + Send + 'static,
+{
+    let (engine_stdin, fake_stdin) = duplex(4096);
+    let (mut fake_stdout, engine_stdout) = duplex(4096);
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(fake_stdin).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            for reply in respond(&line) {
+                if fake_stdout
+                    .write_all(format!("{reply}\n").as_bytes())
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+    (
+        BufWriter::new(Box::new(engine_stdin)),
+        BufReader::new(Box::new(engine_stdout)),
+    )
+}
+
+/// Builds a plain `go` limited by depth and/or think time.
+fn go_command(depth: Option<u32>, movetime: Option<Duration>) -> UciIn {
+    UciIn::Go {
+        searchmoves: None,
+        ponder: false,
+        wtime: None,
+        btime: None,
+        winc: None,
+        binc: None,
+        movestogo: None,
+        depth,
+        nodes: None,
+        mate: None,
+        movetime,
+        infinite: false,
+        perft: None,
+    }
+}
+
+/// Finds the network file in lines like
+/// `info string NNUE evaluation using nn-ad9b42354671.nnue enabled`.
+fn nnue_net(line: &str) -> Option<&str> {
+    line.split_whitespace()
+        .find(|token| token.ends_with(".nnue"))
+}
+
+fn dry_run_reply(command: &str) -> Vec<String> {
+    let replies: &[&str] = match command.split_whitespace().next() {
+        Some("uci") => &[
+            "id name dry-run",
+            "option name Threads type spin default 1 min 1 max 512",
+            "option name Hash type spin default 16 min 1 max 33554432",
+            "uciok",
+        ],
+        Some("isready") => &["readyok"],
+        Some("go") => &["bestmove 0000"],
+        _ => &[],
+    };
+    replies.iter().map(|reply| reply.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    fn params() -> EngineParameters {
+        EngineParameters {
+            args: Vec::new(),
+            sandbox: None,
+            kind: EngineKind::AlphaBeta,
+            max_threads: 4,
+            max_hash: 256,
+            max_hash_fraction: None,
+            blocked_options: HashSet::new(),
+            overlay_options: HashSet::new(),
+            on_out_of_range: OnOutOfRange::Reject,
+            unknown_option_policy: UnknownOptionPolicy::Ignore,
+            synthetic_bestmove_on_exit: false,
+            supports_ucinewgame: true,
+            always_send_stop: false,
+            auto_stop_on_go: false,
+            timeouts: Timeouts::default(),
+            log_format: LogFormat::Plain,
+            log_raw_lines: false,
+            quiet_options: false,
+            max_pv_length: None,
+            max_nodes: None,
+            ready_before_go: false,
+            sync_after_bestmove: false,
+            max_search_time: None,
+            min_search_time: None,
+            search_limit: None,
+            position_transform: None,
+            max_position_moves: None,
+            reject_illegal_bestmove: false,
+            rlimits: None,
+            spawn_retry: None,
+            crash_breaker: None,
+            memory_guard: None,
+            recorder: None,
+            warmup: false,
+        }
+    }
+
+    async fn mock_engine<F>(params: EngineParameters, respond: F) -> io::Result<Engine>
+    where
+        F: FnMut(&str) -> Vec<String> + Send + 'static,
+    {
+        let (stdin, stdout) = fake_process(respond);
+        Engine::from_io(
+            PathBuf::new(),
+            None,
+            stdin,
+            stdout,
+            params,
+            HashMap::new(),
+            |_| (),
+        )
+        .await
+    }
+
+    fn command(line: &str) -> UciIn {
+        UciIn::from_line(line)
+            .expect("valid command")
+            .expect("non-empty")
+    }
+
+    fn command_out(line: &str) -> UciOut {
+        UciOut::from_line(line)
+            .expect("valid output")
+            .expect("non-empty")
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_options_after_uciok() -> io::Result<()> {
+        let mut atomic = false;
+        let mut engine = mock_engine(params(), move |command| match command {
+            "uci" if atomic => lines(&[
+                "id name Fairy-Stockfish",
+                "option name Threads type spin default 1 min 1 max 512",
+                "option name UCI_Variant type combo default atomic var chess var atomic var crazyhouse",
+                "option name Atomic Blast type check default true",
+                "uciok",
+            ]),
+            "uci" => lines(&[
+                "id name Fairy-Stockfish",
+                "option name Threads type spin default 1 min 1 max 512",
+                "option name UCI_Variant type combo default chess var chess var atomic",
+                "uciok",
+            ]),
+            "setoption name UCI_Variant value atomic" => {
+                atomic = true;
+                lines(&[
+                    "option name UCI_Variant type combo default atomic var chess var atomic var crazyhouse",
+                    "option name Atomic Blast type check default true",
+                ])
+            }
+            "isready" => lines(&["readyok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+        assert_eq!(engine.variants(), ["chess", "atomic"]);
+
+        let session = Session(1);
+        engine.set_option(session, "UCI_Variant", "atomic").await?;
+        engine.send(session, UciIn::Isready).await?;
+        engine.ensure_idle(session).await?;
+
+        assert_eq!(engine.variants(), ["chess", "atomic", "crazyhouse"]);
+        assert!(engine.has_option("Atomic Blast"));
+        assert_eq!(engine.max_threads(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_variant_refreshes_options() -> io::Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut variant = "chess";
+        let mut engine = mock_engine(
+            EngineParameters {
+                unknown_option_policy: UnknownOptionPolicy::Error,
+                ..params()
+            },
+            move |command| {
+                let _ = tx.send(command.to_owned());
+                match command {
+                    "uci" => {
+                        let mut out = lines(&[
+                            "id name Fairy-Stockfish",
+                            "option name UCI_Variant type combo default chess var chess var crazyhouse var shogi",
+                        ]);
+                        out.push(match variant {
+                            "chess" => "option name Chess Only type check default false".to_owned(),
+                            _ => "option name Drop Rule type combo default any var any var none"
+                                .to_owned(),
+                        });
+                        out.push("uciok".to_owned());
+                        out
+                    }
+                    "setoption name UCI_Variant value crazyhouse" => {
+                        variant = "crazyhouse";
+                        Vec::new()
+                    }
+                    "isready" => lines(&["readyok"]),
+                    _ => Vec::new(),
+                }
+            },
+        )
+        .await?;
+        assert!(engine.has_option("Chess Only"));
+        while rx.try_recv().is_ok() {}
+
+        let session = Session(1);
+        engine
+            .set_option(session, "UCI_Variant", "crazyhouse")
+            .await?;
+        // Validated against the options of the new variant.
+        let setoption = |name: &str, value: &str| UciIn::Setoption {
+            name: UciOptionName(name.to_owned()),
+            value: Some(value.to_owned()),
+        };
+        engine
+            .send_dangerous(session, setoption("Drop Rule", "none"))
+            .await?;
+        engine.send(session, UciIn::Isready).await?;
+        // The output of the refresh is not passed on.
+        assert!(matches!(engine.recv(session).await?, UciOut::Readyok));
+        assert!(engine.has_option("Drop Rule"));
+        assert!(!engine.has_option("Chess Only"));
+
+        assert!(engine
+            .send_dangerous(session, setoption("Chess Only", "true"))
+            .await
+            .is_err());
+        engine
+            .send_dangerous(session, setoption("Drop Rule", "any"))
+            .await?;
+        // Unchanged, so no refresh.
+        engine
+            .set_option(session, "UCI_Variant", "crazyhouse")
+            .await?;
+        engine.send(session, UciIn::Isready).await?;
+        engine.ensure_idle(session).await?;
+
+        let mut sent = Vec::new();
+        while let Ok(command) = rx.try_recv() {
+            sent.push(command);
+        }
+        assert_eq!(
+            sent,
+            [
+                "setoption name UCI_Variant value crazyhouse",
+                "uci",
+                "setoption name Drop Rule value none",
+                "isready",
+                "setoption name Drop Rule value any",
+                "setoption name UCI_Variant value crazyhouse",
+                "isready",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_select_variant() -> io::Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut engine = mock_engine(params(), move |command| {
+            let _ = tx.send(command.to_owned());
+            match command {
+                "uci" => lines(&[
+                    "id name Fairy-Stockfish",
+                    "option name UCI_Variant type combo default chess var chess var Atomic",
+                    "uciok",
+                ]),
+                "isready" => lines(&["readyok"]),
+                _ => Vec::new(),
+            }
+        })
+        .await?;
+        let session = Session(1);
+        engine.select_variant(session, "atomic").await?;
+        let err = engine
+            .select_variant(session, "horde")
+            .await
+            .expect_err("unsupported");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::UnsupportedVariant(variant)) if variant == "horde"
+        ));
+        engine.ensure_idle(session).await?;
+        let mut sent = Vec::new();
+        while let Ok(command) = rx.try_recv() {
+            sent.push(command);
+        }
+        assert!(sent.contains(&"setoption name UCI_Variant value Atomic".to_owned()));
+
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Stockfish", "uciok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+        engine.select_variant(session, "chess").await?;
+        assert!(engine.select_variant(session, "atomic").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_uci() {
+        let err = mock_engine(params(), |command| match command {
+            "uci" => vec!["Error (unknown command): uci".to_owned(); 20],
+            _ => Vec::new(),
+        })
+        .await
+        .err()
+        .expect("not uci");
+        match err.get_ref().and_then(|err| err.downcast_ref()) {
+            Some(EngineError::NotUci(output)) => assert_eq!(output.len(), MAX_NON_UCI_LINES),
+            other => panic!("expected not uci: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        assert_eq!(engine.name(), Some("dry-run"));
+        assert_eq!(engine.max_hash(), 256);
+
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        engine
+            .send(session, command("position startpos moves e2e4"))
+            .await?;
+        engine.send(session, command("go depth 1")).await?;
+        assert_eq!(
+            engine.recv(session).await?,
+            UciOut::Bestmove {
+                m: None,
+                ponder: None
+            }
+        );
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_warmup() -> io::Result<()> {
+        let engine = Engine::dry_run(EngineParameters {
+            warmup: true,
+            ..params()
+        })
+        .await?;
+        assert!(engine.can_accept_search());
+        assert!(engine.engine_state().position.is_none());
+        assert!(engine.bytes_written() > "uci\r\n".len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_hash_fraction() -> io::Result<()> {
+        let engine = Engine::dry_run(EngineParameters {
+            max_hash_fraction: Some(0.25),
+            ..params()
+        })
+        .await?;
+        assert!(engine.max_hash() <= 256);
+
+        let engine = Engine::dry_run(EngineParameters {
+            max_hash_fraction: Some(1e-12),
+            ..params()
+        })
+        .await?;
+        assert_eq!(engine.max_hash(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_current_value() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        assert_eq!(engine.current_threads(), Some(1));
+
+        let session = Session(1);
+        engine.set_option(session, "Threads", "3").await?;
+        assert_eq!(engine.current_threads(), Some(3));
+        assert!(engine.set_option(session, "Threads", "5").await.is_err());
+        assert_eq!(engine.current_threads(), Some(3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        engine.reconfigure(session, Some(16), Some(128)).await?;
+        assert!(engine.is_idle());
+        assert_eq!(engine.current_threads(), Some(4));
+        assert_eq!(engine.current_hash(), Some(128));
+
+        engine.reconfigure(session, None, Some(0)).await?;
+        assert_eq!(engine.current_threads(), Some(4));
+        assert_eq!(engine.current_hash(), Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clamp_out_of_range() -> io::Result<()> {
+        let mut engine = Engine::dry_run(EngineParameters {
+            on_out_of_range: OnOutOfRange::Clamp,
+            ..params()
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.set_option(session, "Threads", "5").await?;
+        assert_eq!(engine.current_threads(), Some(4));
+        engine.set_option(session, "Hash", "0").await?;
+        assert_eq!(engine.current_hash(), Some(1));
+        assert!(engine.set_option(session, "Hash", "lots").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            "isready" => lines(&["readyok"]),
+            "go depth 1" => lines(&["info depth 1 score cp 20 pv e2e4", "bestmove e2e4"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go depth 1")).await?;
+        engine.drain();
+        assert!(!engine.is_drained());
+        engine.send(session, UciIn::Isready).await?;
+        engine.drained(session).await?;
+        assert!(engine.is_drained());
+        assert_eq!(
+            engine.last_search_summary().bestmove,
+            Some("e2e4".parse().unwrap())
+        );
+
+        let err = engine
+            .send(session, command("go depth 1"))
+            .await
+            .expect_err("draining");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::Draining)
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "validation")]
+    #[tokio::test]
+    async fn test_illegal_bestmove() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                reject_illegal_bestmove: true,
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go depth 1" => lines(&["bestmove e7e5"]),
+                "go depth 2" => lines(&["bestmove g8f6"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine
+            .send(session, command("position startpos moves e2e4"))
+            .await?;
+        engine.send(session, command("go depth 1")).await?;
+        assert!(matches!(
+            engine.recv(session).await?,
+            UciOut::Bestmove { .. }
+        ));
+
+        engine
+            .send(session, command("position startpos moves e2e4 e7e5"))
+            .await?;
+        engine.send(session, command("go depth 2")).await?;
+        let err = engine.recv(session).await.expect_err("illegal");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::IllegalBestmove(_))
+        ));
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_nodes() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                max_nodes: Some(1_000_000),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go nodes 1000000 movetime 1000" => lines(&["bestmove e2e4"]),
+                "go nodes 5000" => lines(&["bestmove d2d4"]),
+                "isready" => lines(&["readyok"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine
+            .send(session, command("go movetime 1000 nodes 1000000000"))
+            .await?;
+        engine.ensure_idle(session).await?;
+        assert_eq!(
+            engine.last_search_summary().bestmove,
+            Some("e2e4".parse().unwrap())
+        );
+
+        engine.send(session, command("go nodes 5000")).await?;
+        engine.ensure_idle(session).await?;
+        assert_eq!(
+            engine.last_search_summary().bestmove,
+            Some("d2d4".parse().unwrap())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_position_transform() -> io::Result<()> {
+        fn mirror(uci: Uci) -> Uci {
+            match uci {
+                Uci::Normal {
+                    from,
+                    to,
+                    promotion,
+                } => Uci::Normal {
+                    from: from.flip_horizontal(),
+                    to: to.flip_horizontal(),
+                    promotion,
+                },
+                uci => uci,
+            }
+        }
+
+        let mut engine = mock_engine(
+            EngineParameters {
+                position_transform: Some(PositionTransform {
+                    position: Box::new(|pos| pos),
+                    uci: Box::new(mirror),
+                }),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "position fen rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1" => {
+                    Vec::new()
+                }
+                "go depth 1" => lines(&["info depth 1 score cp 0 pv b8c6", "bestmove b8c6"]),
+                other => panic!("unexpected command: {other}"),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine
+            .send(session, command("position startpos moves e2e4"))
+            .await?;
+        engine.send(session, command("go depth 1")).await?;
+        assert_eq!(
+            engine.recv(session).await?,
+            command_out("info depth 1 score cp 0 pv g8f6")
+        );
+        assert_eq!(engine.recv(session).await?, command_out("bestmove g8f6"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_position_moves() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                max_position_moves: Some(2),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "position startpos moves e2e4 e7e5"
+                | "position fen rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2 moves g1f3 b8c6"
+                | "position fen 8/8/8/8/8/8/8/8 w - - 0 1 moves a1a2 a2a3 a3a4" => Vec::new(),
+                "isready" => lines(&["readyok"]),
+                other => panic!("unexpected command: {other}"),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine
+            .send(session, command("position startpos moves e2e4 e7e5"))
+            .await?;
+        engine
+            .send(
+                session,
+                command("position startpos moves e2e4 e7e5 g1f3 b8c6"),
+            )
+            .await?;
+        // Not a legal position, so sent unchanged.
+        engine
+            .send(
+                session,
+                command("position fen 8/8/8/8/8/8/8/8 w - - 0 1 moves a1a2 a2a3 a3a4"),
+            )
+            .await?;
+        engine.send(session, UciIn::Isready).await?;
+        engine.ensure_idle(session).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_silent_search() -> io::Result<()> {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut engine = mock_engine(
+            EngineParameters {
+                timeouts: Timeouts {
+                    silent: Some(Duration::from_millis(50)),
+                    ..Timeouts::default()
+                },
+                ..params()
+            },
+            {
+                let stopped = Arc::clone(&stopped);
+                move |command| match command {
+                    "uci" => lines(&["id name Mock", "uciok"]),
+                    "stop" => {
+                        stopped.store(true, Ordering::SeqCst);
+                        Vec::new()
+                    }
+                    _ => Vec::new(),
+                }
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        let err = engine.recv(session).await.expect_err("silent");
+        assert!(stopped.load(Ordering::SeqCst));
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::Silent)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_limit_released_on_stop_timeout() -> io::Result<()> {
+        let limit = SearchLimit::new(1, SearchLimitPolicy::Reject);
+        let mut engine = mock_engine(
+            EngineParameters {
+                search_limit: Some(limit.clone()),
+                timeouts: Timeouts {
+                    stop: Some(Duration::from_millis(50)),
+                    ..Timeouts::default()
+                },
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        assert_eq!(limit.permits.available_permits(), 0);
+        let err = engine
+            .ensure_stopped(session)
+            .await
+            .expect_err("ignores stop");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(limit.permits.available_permits(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recv_timeout_partial_line() -> io::Result<()> {
+        let (engine_stdin, _fake_stdin) = duplex(64);
+        let (mut fake_stdout, engine_stdout) = duplex(64);
+        let mut engine = Engine::unstarted(
+            PathBuf::new(),
+            None,
+            BufWriter::new(Box::new(engine_stdin)),
+            BufReader::new(Box::new(engine_stdout)),
+            params(),
+        );
+        let session = Session(1);
+        fake_stdout.write_all(b"ready").await?;
+        let err = engine
+            .recv_timeout(session, Some(Duration::from_millis(20)))
+            .await
+            .expect_err("incomplete line");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        fake_stdout.write_all(b"ok\n").await?;
+        assert!(matches!(engine.recv(session).await?, UciOut::Readyok));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() -> io::Result<()> {
+        // The engine never reads its input.
+        let (engine_stdin, _fake_stdin) = duplex(64);
+        let (_fake_stdout, engine_stdout) = duplex(64);
+        let mut engine = Engine::unstarted(
+            PathBuf::new(),
+            None,
+            BufWriter::new(Box::new(engine_stdin)),
+            BufReader::new(Box::new(engine_stdout)),
+            EngineParameters {
+                timeouts: Timeouts {
+                    write: Some(Duration::from_millis(50)),
+                    ..Timeouts::default()
+                },
+                ..params()
+            },
+        );
+        let session = Session(1);
+        let moves = vec!["e2e4 e7e5 g1f3 b8c6"; 50].join(" ");
+        let err = engine
+            .send_dangerous(
+                session,
+                command(&format!("position startpos moves {moves}")),
+            )
+            .await
+            .expect_err("blocked");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(engine.is_exited());
+        let err = engine
+            .send_dangerous(session, UciIn::Isready)
+            .await
+            .expect_err("engine killed");
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_search_time() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                max_search_time: Some(Duration::from_millis(50)),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go movetime 50" => lines(&["bestmove e2e4"]),
+                "stop" => lines(&["bestmove d2d4"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        assert_eq!(engine.recv(session).await?, command_out("bestmove d2d4"));
+        assert!(engine.is_idle());
+
+        engine.send(session, command("go movetime 60000")).await?;
+        assert_eq!(engine.recv(session).await?, command_out("bestmove e2e4"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_search_time() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                min_search_time: Some(Duration::from_millis(50)),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go infinite" => lines(&["info depth 1 score cp 20 pv e2e4"]),
+                "stop" => lines(&["bestmove e2e4"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        let started = Instant::now();
+        engine.send(session, command("go infinite")).await?;
+        engine.send(session, UciIn::Stop).await?;
+        assert!(engine.is_searching());
+        assert!(matches!(engine.recv(session).await?, UciOut::Info { .. }));
+        assert_eq!(engine.recv(session).await?, command_out("bestmove e2e4"));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_current_best_first_move() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            "go depth 2" => lines(&[
+                "info depth 1 score cp 20 pv e2e4 e7e5",
+                "info depth 2 multipv 2 score cp 10 pv d2d4",
+                "info depth 2 score cp 30 pv g1f3",
+                "bestmove g1f3",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+        assert_eq!(engine.current_best_first_move(), None);
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go depth 2")).await?;
+        assert_eq!(engine.current_best_first_move(), None);
+        engine.recv(session).await?;
+        assert_eq!(
+            engine.current_best_first_move(),
+            Some("e2e4".parse().expect("uci"))
+        );
+        engine.recv(session).await?;
+        assert_eq!(
+            engine.current_best_first_move(),
+            Some("e2e4".parse().expect("uci"))
+        );
+        engine.recv(session).await?;
+        assert_eq!(
+            engine.current_best_first_move(),
+            Some("g1f3".parse().expect("uci"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_stop_on_go() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                auto_stop_on_go: true,
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go infinite" => lines(&["info depth 1 score cp 20 pv e2e4"]),
+                "stop" => lines(&["bestmove e2e4"]),
+                "go depth 1" => lines(&["bestmove d2d4"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        assert!(engine
+            .send(Session(2), command("position startpos moves e2e4"))
+            .await
+            .is_err());
+        engine
+            .send(session, command("position startpos moves e2e4"))
+            .await?;
+        assert!(!engine.is_searching());
+        engine.send(session, command("go depth 1")).await?;
+        assert_eq!(engine.recv(session).await?, command_out("bestmove d2d4"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_options() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "id name Mock",
+                "option name MultiPV type spin default 1 min 1 max 500",
+                "option name Hash type spin default 16 min 1 max 1024",
+                "uciok",
+            ]),
+            "isready" => lines(&["readyok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.set_option(session, "MultiPV", "5").await?;
+        engine.set_option(session, "Hash", "64").await?;
+
+        let session = Session(2);
+        engine.ensure_newgame(session).await?;
+        assert_eq!(
+            engine.current_value(&UciOptionName("MultiPV".to_owned())),
+            Some("1".to_owned())
+        );
+        assert_eq!(engine.current_hash(), Some(64));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_engine_info() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "Stockfish 15 by the Stockfish developers (see AUTHORS file)",
+                "id name Stockfish 15",
+                "id author the Stockfish developers (see AUTHORS file)",
+                "uciok",
+            ]),
+            "isready" => lines(&[
+                "info string NNUE evaluation using nn-6877cd24400e.nnue enabled",
+                "readyok",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, UciIn::Isready).await?;
+        engine.ensure_idle(session).await?;
+        let info = engine.engine_info();
+        assert_eq!(info.name.as_deref(), Some("Stockfish 15"));
+        assert_eq!(info.version.as_deref(), Some("15"));
+        assert_eq!(info.nnue.as_deref(), Some("nn-6877cd24400e.nnue"));
+        assert_eq!(info.banner.len(), 1);
+
+        let engine = Engine::dry_run(params()).await?;
+        let info = engine.engine_info();
+        assert_eq!(info.name.as_deref(), Some("dry-run"));
+        assert_eq!(info.version, None);
+        assert_eq!(info.nnue, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_analyze_bestmove_only() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            "go depth 2" => lines(&[
+                "info depth 1 score cp 20 pv e2e4",
+                "info depth 2 score cp 30 pv d2d4 d7d5",
+                "bestmove d2d4 ponder d7d5",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        let m = engine
+            .analyze(
+                session,
+                command("position startpos"),
+                command("go depth 2"),
+                true,
+            )
+            .await?;
+        assert_eq!(m, Some("d2d4".parse().unwrap()));
+        assert_eq!(engine.last_search_summary().depth, None);
+
+        let m = engine
+            .analyze(
+                session,
+                command("position startpos"),
+                command("go depth 2"),
+                false,
+            )
+            .await?;
+        assert_eq!(m, Some("d2d4".parse().unwrap()));
+        assert_eq!(engine.last_search_summary().depth, Some(2));
+        assert!(engine.last_search_wallclock().is_some());
+        assert_eq!(engine.metrics().searches, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() -> io::Result<()> {
+        fn respond(command: &str) -> Vec<String> {
+            match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go depth 10" => (1..=10)
+                    .map(|depth| format!("info depth {depth} score cp 0 pv e2e4"))
+                    .chain(Some("bestmove e2e4".to_owned()))
+                    .collect(),
+                _ => Vec::new(),
+            }
+        }
+
+        for backpressure in [Backpressure::Block, Backpressure::DropInfo] {
+            let mut engine = mock_engine(params(), respond).await?;
+            let session = Session(1);
+            engine.send(session, command("position startpos")).await?;
+            engine.send(session, command("go depth 10")).await?;
+
+            // Slow consumer.
+            let (tx, mut rx) = mpsc::channel(1);
+            let consumer = tokio::spawn(async move {
+                let mut received = Vec::new();
+                while let Some(command) = rx.recv().await {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    received.push(command);
+                }
+                received
+            });
+            engine.forward(session, &tx, backpressure).await?;
+            drop(tx);
+
+            let received = consumer.await.expect("consumer");
+            assert!(matches!(received.last(), Some(UciOut::Bestmove { .. })));
+            match backpressure {
+                Backpressure::Block => assert_eq!(received.len(), 11),
+                Backpressure::DropInfo => assert!(received.len() < 11),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_best_move() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            "isready" => lines(&["readyok"]),
+            "go movetime 100" => lines(&["info depth 1 score cp 0 pv e7e5", "bestmove e7e5"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        let movetime = Duration::from_millis(100);
+        let m = engine
+            .best_move(session, command("position startpos moves e2e4"), movetime)
+            .await?;
+        assert_eq!(m, Some("e7e5".parse().unwrap()));
+        let written = engine.bytes_written();
+
+        // Continuing the game does not start a new one.
+        engine
+            .best_move(
+                session,
+                command("position startpos moves e2e4 e7e5 g1f3"),
+                movetime,
+            )
+            .await?;
+        assert_eq!(
+            engine.bytes_written() - written,
+            ("position startpos moves e2e4 e7e5 g1f3\r\n".len() + "go movetime 100\r\n".len())
+                as u64
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_owner() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            "stop" => lines(&["bestmove e2e4"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let a = Session(1);
+        let b = Session(2);
+        engine.send(a, command("position startpos")).await?;
+        engine.send(a, command("go infinite")).await?;
+        assert_eq!(engine.search_owner(), Some(a));
+
+        // Does not touch the search of a.
+        let written = engine.bytes_written();
+        engine.send(b, UciIn::Stop).await?;
+        assert!(engine.is_searching());
+        assert_eq!(engine.bytes_written(), written);
+
+        engine.send(a, UciIn::Stop).await?;
+        assert_eq!(engine.recv(a).await?, command_out("bestmove e2e4"));
+        assert!(!engine.is_searching());
+        assert_eq!(engine.search_owner(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_debug_state() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+        let state = engine.debug_state();
+        assert_eq!(state.last_sent.as_deref(), Some("uci"));
+        assert_eq!(state.last_received.as_deref(), Some("uciok"));
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, UciIn::Isready).await?;
+        let state = engine.debug_state();
+        assert_eq!(state.pending_uciok, 0);
+        assert_eq!(state.pending_readyok, 1);
+        assert!(!state.searching);
+        assert_eq!(state.last_sent.as_deref(), Some("isready"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_when_idle() -> io::Result<()> {
+        for always_send_stop in [false, true] {
+            let mut engine = mock_engine(
+                EngineParameters {
+                    always_send_stop,
+                    ..params()
+                },
+                |command| match command {
+                    "uci" => lines(&["id name Mock", "uciok"]),
+                    _ => Vec::new(),
+                },
+            )
+            .await?;
+
+            let written = engine.bytes_written();
+            engine.send(Session(1), UciIn::Stop).await?;
+            assert_eq!(engine.bytes_written() > written, always_send_stop);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_overlay() -> io::Result<()> {
+        let skill = UciOptionName("Skill Level".to_owned());
+        let mut engine = mock_engine(
+            EngineParameters {
+                overlay_options: HashSet::from([skill.clone()]),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&[
+                    "id name Mock",
+                    "option name Skill Level type spin default 20 min 0 max 20",
+                    "option name MultiPV type spin default 1 min 1 max 500",
+                    "uciok",
+                ]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let a = Session(1);
+        let b = Session(2);
+
+        // Not settable globally by clients.
+        engine
+            .send(a, command("setoption name Skill Level value 10"))
+            .await?;
+        assert_eq!(engine.current_value(&skill), Some("20".to_owned()));
+
+        let err = engine
+            .set_session_option(a, UciOptionName("MultiPV".to_owned()), Some("3".to_owned()))
+            .await
+            .expect_err("not an overlay option");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::NotOverlayOption(_))
+        ));
+        assert!(engine
+            .set_session_option(a, skill.clone(), Some("21".to_owned()))
+            .await
+            .is_err());
+
+        engine
+            .set_session_option(a, skill.clone(), Some("10".to_owned()))
+            .await?;
+        assert_eq!(engine.current_value(&skill), Some("20".to_owned()));
+
+        engine.activate_session(a).await?;
+        assert_eq!(engine.current_value(&skill), Some("10".to_owned()));
+        let written = engine.bytes_written();
+        engine.activate_session(a).await?;
+        assert_eq!(engine.bytes_written(), written);
+
+        engine.activate_session(b).await?;
+        assert_eq!(engine.current_value(&skill), Some("20".to_owned()));
+
+        engine.activate_session(a).await?;
+        assert_eq!(engine.current_value(&skill), Some("10".to_owned()));
+        engine.forget_session(a);
+        engine.activate_session(b).await?;
+        assert_eq!(engine.current_value(&skill), Some("20".to_owned()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_stdin() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go infinite")).await?;
+        engine.close_stdin(session).await?;
+        assert!(engine.send(session, UciIn::Stop).await.is_err());
+
+        // The fake process exits when it sees the end of input.
+        let err = engine.recv(session).await.expect_err("exited");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::EngineExited)
+        ));
+        assert_eq!(engine.wait(session, None).await?, None);
+        assert!(engine.is_exited());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_profile() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        let session = Session(1);
+        let deep = Profile {
+            name: "deep".to_owned(),
+            threads: Some(4),
+            hash: Some(128),
+            depth: Some(30),
+            ..Profile::default()
+        };
+        engine.apply_profile(session, &deep).await?;
+        assert_eq!(engine.current_threads(), Some(4));
+        assert_eq!(engine.current_hash(), Some(128));
+        assert_eq!(deep.go().to_string(), "go depth 30");
+
+        let written = engine.bytes_written();
+        engine.apply_profile(session, &deep).await?;
+        assert_eq!(engine.bytes_written(), written);
+
+        let teaching = Profile {
+            name: "teaching".to_owned(),
+            threads: Some(1),
+            skill_level: Some(5),
+            ..Profile::default()
+        };
+        assert!(engine.apply_profile(session, &teaching).await.is_err());
+        assert_eq!(engine.current_threads(), Some(4));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_without_resource_options() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Mock", "uciok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        assert_eq!(
+            engine.capabilities(),
+            Capabilities {
+                threads: false,
+                hash: false
+            }
+        );
+        assert_eq!(engine.max_threads(), 1);
+        assert_eq!(engine.max_hash(), 16);
+        assert_eq!(engine.current_threads(), None);
+
+        let written = engine.bytes_written();
+        engine
+            .send(Session(1), command("setoption name Threads value 4"))
+            .await?;
+        assert_eq!(engine.bytes_written(), written);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_option_sync() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "id name Mock",
+                "option name Hash type spin default 16 min 1 max 1024",
+                "uciok",
+            ]),
+            "isready" => lines(&["readyok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine
+            .set_option_sync(
+                session,
+                UciOptionName("Hash".to_owned()),
+                Some("128".to_owned()),
+            )
+            .await?;
+        assert!(engine.is_idle());
+        assert_eq!(engine.current_hash(), Some(128));
+        assert_eq!(
+            engine.bytes_written(),
+            ("uci\r\n".len() + "setoption name Hash value 128\r\n".len() + "isready\r\n".len())
+                as u64
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_option_policy() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        let session = Session(1);
+        engine
+            .send(session, command("setoption name Hahs value 64"))
+            .await?;
+
+        let mut engine = Engine::dry_run(EngineParameters {
+            unknown_option_policy: UnknownOptionPolicy::Error,
+            ..params()
+        })
+        .await?;
+        let err = engine
+            .send(session, command("setoption name Hahs value 64"))
+            .await
+            .expect_err("unknown option");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::UnknownOption(name)) if name.0 == "Hahs"
+        ));
+        engine
+            .send(session, command("setoption name Hash value 64"))
+            .await?;
+        assert_eq!(engine.current_hash(), Some(64));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crash_breaker() -> io::Result<()> {
+        let mut engine = Engine::dry_run(EngineParameters {
+            crash_breaker: Some(CrashBreaker {
+                max_crashes: 2,
+                window: Duration::from_secs(60),
+                cooldown: Duration::from_millis(50),
+            }),
+            ..params()
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.record_crash(session);
+        assert_eq!(
+            engine.breaker_state(),
+            BreakerState::Closed { recent_crashes: 1 }
+        );
+        engine.record_crash(session);
+        assert!(matches!(engine.breaker_state(), BreakerState::Open { .. }));
+        let err = engine.restart(session).await.expect_err("quarantined");
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref()),
+            Some(EngineError::Quarantined(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(
+            engine.breaker_state(),
+            BreakerState::Closed { recent_crashes: 0 }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_normalized_variants() -> io::Result<()> {
+        let engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "id name Fairy-Stockfish 14.0.1 LB",
+                "option name UCI_Variant type combo default chess var 3check var 5check var ai-wok var almost var amazon var antichess var armageddon var asean var atomic var breakthrough var bughouse var cambodian var capablanca var capahouse var caparandom var chaturanga var chess var chessgi var coregal var crazyhouse var embassy var euroshogi var extinction var fairy var fischerandom var gardner var giveaway var gothic var grasshopper var horde var janus var judkins var kinglet var kingofthehill var knightmate var koth var loop var losalamos var losers var makpong var makruk var manchu var micro var mini var minishogi var minixiangqi var modern var nocastle var nocheckatomic var placement var pocketknight var racingkings var seirawan var shako var shatar var shatranj var shogi var shouse var sittuyin var suicide var supply var threekings var xiangqi var Chess var atomic",
+                "uciok",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let raw = engine.variants();
+        let variants = engine.normalized_variants();
+        assert_eq!(variants.len(), raw.len() - 3);
+        assert_eq!(variants.first().map(String::as_str), Some("3check"));
+        assert_eq!(variants.last().map(String::as_str), Some("xiangqi"));
+        assert!(variants.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!variants.iter().any(|variant| variant == "fairy"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_analysis_request() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                max_nodes: Some(1_000_000),
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go nodes 1000000" => lines(&["bestmove e2e4"]),
+                "go movetime 3000" => lines(&["bestmove d2d4"]),
+                "go movetime 1" | "go depth 1" => lines(&["bestmove g1f3"]),
+                "isready" => lines(&["readyok"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        assert_eq!(
+            engine
+                .go(session, AnalysisRequest::ToDepth(22))?
+                .to_string(),
+            "go depth 22"
+        );
+        assert_eq!(
+            engine.go(session, AnalysisRequest::Mate(3))?.to_string(),
+            "go mate 3"
+        );
+        assert_eq!(
+            engine.go(session, AnalysisRequest::Infinite)?.to_string(),
+            "go infinite"
+        );
+        assert!(engine.go(session, AnalysisRequest::ToDepth(0)).is_err());
+        assert!(engine
+            .go(session, AnalysisRequest::ForTime(Duration::ZERO))
+            .is_err());
+
+        let m = engine
+            .analyze_request(
+                session,
+                command("position startpos"),
+                AnalysisRequest::ForNodes(5_000_000),
+                true,
+            )
+            .await?;
+        assert_eq!(m, Some("e2e4".parse().unwrap()));
+        let m = engine
+            .analyze_request(
+                session,
+                command("position startpos"),
+                AnalysisRequest::ForTime(Duration::from_secs(3)),
+                true,
+            )
+            .await?;
+        assert_eq!(m, Some("d2d4".parse().unwrap()));
+
+        // Instant replies.
+        for request in [
+            AnalysisRequest::ForTime(Duration::from_micros(500)),
+            AnalysisRequest::ToDepth(1),
+        ] {
+            let m = engine
+                .analyze_request(session, command("position startpos"), request, true)
+                .await?;
+            assert_eq!(m, Some("g1f3".parse().unwrap()));
+            assert!(engine.is_idle());
+        }
+
+        assert!(engine
+            .analyze_request(
+                session,
+                command("position startpos"),
+                AnalysisRequest::Infinite,
+                true
+            )
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_enable_wdl() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "id name Stockfish 16",
+                "option name UCI_ShowWDL type check default false",
+                "uciok",
+            ]),
+            "isready" => lines(&["readyok"]),
+            _ => Vec::new(),
+        })
+        .await?;
+        assert!(engine.supports_wdl());
+        let wdl = UciOptionName("UCI_ShowWDL".to_owned());
+        assert_eq!(engine.current_value(&wdl), Some("false".to_owned()));
+
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        assert!(engine.enable_wdl(session).await?);
+        assert_eq!(engine.current_value(&wdl), Some("true".to_owned()));
+        let written = engine.bytes_written();
+        assert!(engine.enable_wdl(session).await?);
+        assert_eq!(engine.bytes_written(), written);
+
+        engine.ensure_newgame(Session(2)).await?;
+        assert_eq!(engine.current_value(&wdl), Some("false".to_owned()));
+
+        let mut engine = Engine::dry_run(params()).await?;
+        assert!(!engine.supports_wdl());
+        assert!(!engine.enable_wdl(session).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_neural_net() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                kind: EngineKind::NeuralNet,
+                timeouts: Timeouts {
+                    silent: Some(Duration::from_millis(20)),
+                    ..Timeouts::default()
+                },
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&[
+                    "id name Mock",
+                    "option name UCI_ShowWDL type check default false",
+                    "uciok",
+                ]),
+                "isready" => lines(&["readyok"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+        let wdl = UciOptionName("UCI_ShowWDL".to_owned());
+        assert_eq!(engine.current_value(&wdl), Some("true".to_owned()));
+
+        // Survives a silent first search.
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        assert_eq!(engine.current_value(&wdl), Some("true".to_owned()));
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go nodes 1")).await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), engine.recv(session))
+                .await
+                .is_err()
+        );
+        assert!(!engine.silent_stop_sent);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_command_filter() -> io::Result<()> {
+        let mut engine = Engine::dry_run(params()).await?;
+        engine.set_command_filter(|command| match command {
+            UciIn::Setoption { name, value } if *name == "Threads" => {
+                *value = Some("2".to_owned());
+                Decision::Allow
+            }
+            UciIn::Ucinewgame => Decision::Deny,
+            _ => Decision::Allow,
+        });
+
+        let session = Session(1);
+        engine.set_option(session, "Threads", "4").await?;
+        assert_eq!(engine.current_threads(), Some(2));
+        let written = engine.bytes_written();
+        engine.send(session, UciIn::Ucinewgame).await?;
+        assert_eq!(engine.bytes_written(), written);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_tee() -> io::Result<()> {
+        fn respond(command: &str) -> Vec<String> {
+            match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "go depth 2" => lines(&[
+                    "info depth 1 currmove e2e4",
+                    "info depth 2 score cp 30 pv d2d4 d7d5",
+                    "bestmove d2d4",
+                ]),
+                _ => Vec::new(),
+            }
+        }
+
+        for include_noise in [false, true] {
+            let mut engine = mock_engine(
+                EngineParameters {
+                    max_pv_length: Some(1),
+                    ..params()
+                },
+                respond,
+            )
+            .await?;
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            engine.set_output_tee(
+                move |command| {
+                    let _ = tx.send(command);
+                },
+                include_noise,
+            );
+
+            let session = Session(1);
+            engine
+                .analyze(
+                    session,
+                    command("position startpos"),
+                    command("go depth 2"),
+                    false,
+                )
+                .await?;
+            let mut recorded = Vec::new();
+            while let Ok(command) = rx.try_recv() {
+                recorded.push(command);
+            }
+            assert_eq!(recorded.len(), if include_noise { 3 } else { 2 });
+            assert!(recorded.contains(&command_out("info depth 2 score cp 30 pv d2d4 d7d5")));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "stockfish")]
+    #[tokio::test]
+    async fn test_static_eval() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Stockfish 16", "uciok"]),
+            "isready" => lines(&["readyok"]),
+            "eval" => lines(&[
+                " NNUE network contributions (White to move)",
+                "+------------+------------+------------+------------+",
+                "|  7         |     0.00   |  +  0.10   |  +  0.10   | <-- this bucket is used",
+                "+------------+------------+------------+------------+",
+                "",
+                "NNUE evaluation        +0.10 (white side)",
+                "Final evaluation       +0.09 (white side) [with scaled NNUE, ...]",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        let eval = engine
+            .static_eval(session, command("position startpos"))
+            .await?;
+        assert_eq!(
+            eval,
+            StaticEval {
+                classical: None,
+                nnue: Some(10),
+                total: Some(9),
+            }
+        );
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[cfg(feature = "stockfish")]
+    #[tokio::test]
+    async fn test_bench() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&["id name Stockfish 16", "uciok"]),
+            "isready" => lines(&["readyok"]),
+            "bench" => lines(&[
+                "info depth 1 seldepth 1 multipv 1 score cp 20 nodes 20 nps 20000 time 1 pv e2e4",
+                "info depth 2 seldepth 2 multipv 1 score cp 25 nodes 60 nps 30000 time 2 pv e2e4",
+                "bestmove e2e4",
+                "info depth 1 seldepth 1 multipv 1 score cp -10 nodes 40 nps 40000 time 1 pv e7e5",
+                "bestmove e7e5",
+                "",
+                "===========================",
+                "Total time (ms) : 4210",
+                "Nodes searched  : 2138002",
+                "Nodes/second    : 507838",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        let bench = engine.bench(session, None).await?;
+        assert_eq!(
+            bench,
+            BenchResult {
+                nodes: 2138002,
+                time: Duration::from_millis(4210),
+                nps: 507838,
+            }
+        );
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ready_before_go() -> io::Result<()> {
+        let ready = Arc::new(AtomicBool::new(false));
+        let mut engine = mock_engine(
+            EngineParameters {
+                ready_before_go: true,
+                ..params()
+            },
+            {
+                let ready = Arc::clone(&ready);
+                move |command| match command {
+                    "uci" => lines(&["id name Mock", "uciok"]),
+                    "isready" => {
+                        ready.store(true, Ordering::SeqCst);
+                        lines(&["readyok"])
+                    }
+                    "go depth 1" if ready.swap(false, Ordering::SeqCst) => {
+                        lines(&["bestmove e2e4", "info depth 1 nodes 20 pv e2e4"])
+                    }
+                    _ => Vec::new(),
+                }
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        for _ in 0..2 {
+            engine.send(session, command("position startpos")).await?;
+            engine.send(session, command("go depth 1")).await?;
+            assert_eq!(engine.pending_readyok, 0);
+            assert_eq!(engine.recv(session).await?, command_out("bestmove e2e4"));
+        }
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_string_after_bestmove() -> io::Result<()> {
+        let mut engine = mock_engine(params(), |command| match command {
+            "uci" => lines(&[
+                "id name Mock",
+                "option name EvalFile type string default nn-old.nnue",
+                "uciok",
+            ]),
+            "go depth 1" => lines(&["info depth 1 score cp 20 pv e2e4", "bestmove e2e4"]),
+            "isready" => lines(&[
+                "info string NNUE evaluation using nn-new.nnue enabled",
+                "readyok",
+            ]),
+            _ => Vec::new(),
+        })
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go depth 1")).await?;
+        while engine.is_searching() {
+            engine.recv(session).await?;
+        }
+        engine
+            .send(
+                session,
+                command("setoption name EvalFile value nn-new.nnue"),
+            )
+            .await?;
+        engine.send(session, UciIn::Isready).await?;
+        assert_eq!(
+            engine.recv(session).await?,
+            command_out("info string NNUE evaluation using nn-new.nnue enabled")
+        );
+        assert_eq!(engine.recv(session).await?, UciOut::Readyok);
+        assert_eq!(engine.engine_info().nnue.as_deref(), Some("nn-new.nnue"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_info_after_bestmove() -> io::Result<()> {
+        let mut engine = mock_engine(
+            EngineParameters {
+                sync_after_bestmove: true,
+                ..params()
+            },
+            |command| match command {
+                "uci" => lines(&["id name Mock", "uciok"]),
+                "isready" => lines(&["readyok"]),
+                "go depth 1" => lines(&[
+                    "info depth 1 score cp 20 pv e2e4",
+                    "bestmove e2e4",
+                    "info depth 1 nodes 20 score cp 20 pv e2e4",
+                ]),
+                "go depth 2" => lines(&["info depth 2 score cp 10 pv d2d4", "bestmove d2d4"]),
+                _ => Vec::new(),
+            },
+        )
+        .await?;
+
+        let session = Session(1);
+        engine.send(session, command("position startpos")).await?;
+        engine.send(session, command("go depth 1")).await?;
+        assert_eq!(engine.search_generation(), 1);
+        assert_eq!(
+            engine.recv(session).await?,
+            command_out("info depth 1 score cp 20 pv e2e4")
+        );
+        assert_eq!(engine.recv(session).await?, command_out("bestmove e2e4"));
+
+        // A fast caller starts the next search right away.
+        engine.send(session, command("go depth 2")).await?;
+        assert_eq!(engine.search_generation(), 2);
+        assert_eq!(
+            engine.recv(session).await?,
+            command_out("info depth 2 score cp 10 pv d2d4")
+        );
+        assert_eq!(engine.recv(session).await?, command_out("bestmove d2d4"));
+        assert!(engine.is_idle());
         Ok(())
     }
 }
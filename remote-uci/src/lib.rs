@@ -1,4 +1,9 @@
-mod engine;
+pub mod config;
+mod control;
+pub mod engine;
+pub mod profile;
+pub mod protocol;
+pub mod transcript;
 pub mod uci;
 mod ws;
 
@@ -12,15 +17,20 @@ use std::{
     path::PathBuf,
     sync::Arc,
     thread,
+    time::Duration,
 };
 
 use axum::{
     response::Redirect,
-    routing::{get, IntoMakeService},
+    routing::{get, post, IntoMakeService},
     Router,
 };
 use clap::Parser;
-use engine::EngineParameters;
+use engine::{
+    CrashBreaker, EngineKind, EngineParameters, LogFormat, MemoryGuard, OnOutOfRange,
+    OptionOverride, RLimits, SearchLimit, SearchLimitPolicy, SpawnRetry, Timeouts,
+    UnknownOptionPolicy,
+};
 use hyper::server::conn::AddrIncoming;
 use listenfd::ListenFd;
 use serde::Serialize;
@@ -29,6 +39,7 @@ use sysinfo::{RefreshKind, System, SystemExt};
 use uci::UciOptionName;
 
 use crate::{
+    config::Config,
     engine::Engine,
     ws::{Secret, SharedEngine},
 };
@@ -39,6 +50,10 @@ use crate::{
 pub struct Opts {
     #[clap(flatten)]
     engine: EngineOpts,
+    /// Read the engine and its settings from a TOML or JSON config file,
+    /// instead of the engine flags.
+    #[clap(long)]
+    config: Option<PathBuf>,
     /// Bind server on this socket address.
     #[clap(long)]
     bind: Option<SocketAddr>,
@@ -54,15 +69,148 @@ pub struct Opts {
     /// NNUE eval file path.
     #[clap(long)]
     eval_file: Option<String>,
+    /// Fail to start if the engine does not support --eval-file, rather
+    /// than ignoring it.
+    #[clap(long, requires = "eval-file")]
+    require_eval_file: bool,
     /// Limit number of threads.
     #[clap(long)]
     max_threads: Option<u32>,
     /// Limit size of hash table (MiB).
     #[clap(long)]
     max_hash: Option<u32>,
+    /// Limit size of hash table to this fraction of the total system memory.
+    #[clap(long)]
+    max_hash_fraction: Option<f64>,
+    /// Truncate principal variations sent to clients to this many moves.
+    #[clap(long)]
+    max_pv_length: Option<usize>,
+    /// Limit node budgets requested by clients.
+    #[clap(long)]
+    max_nodes: Option<u64>,
+    /// Wait for the engine to answer isready before every search.
+    #[clap(long)]
+    ready_before_go: bool,
+    /// Pass this flag if the engine prints info after bestmove, so that
+    /// those lines are not mistaken for output of the next search.
+    #[clap(long)]
+    sync_after_bestmove: bool,
+    /// Stop any search after this many seconds, even if the client asked
+    /// for an infinite analysis. 0 for no limit.
+    #[clap(long, default_value_t = 0)]
+    max_search_time: u64,
+    /// Let every search run for at least this many milliseconds before
+    /// stopping it, to smooth out clients that rapidly start and stop
+    /// searches. 0 stops immediately.
+    #[clap(long, default_value_t = 0)]
+    min_search_time: u64,
+    /// Run at most this many searches at the same time. 0 for no limit.
+    #[clap(long, default_value_t = 0)]
+    max_searches: usize,
+    /// What to do with searches beyond --max-searches: wait or reject.
+    #[clap(long, default_value = "wait")]
+    search_limit_policy: SearchLimitPolicy,
+    /// Send at most this many moves to the engine, by playing earlier moves
+    /// into the FEN of the position command. For engines with a limit on the
+    /// game length.
+    #[clap(long)]
+    max_position_moves: Option<usize>,
+    /// Forward at most one batch of analysis updates per interval
+    /// (milliseconds). 0 forwards every update.
+    #[clap(long, default_value_t = 0)]
+    info_interval: u64,
+    /// Pass an argument to the engine process. Can be given multiple times.
+    #[clap(long = "engine-arg", allow_hyphen_values = true)]
+    engine_args: Vec<String>,
+    /// Run the engine through a sandbox wrapper, like bwrap or firejail.
+    /// Give the wrapper and each of its arguments separately. The engine and
+    /// its arguments are appended.
+    #[clap(long = "sandbox", allow_hyphen_values = true)]
+    sandbox: Vec<String>,
+    /// Hide an option advertised by the engine from clients. Can be given
+    /// multiple times.
+    #[clap(long = "block-option")]
+    block_options: Vec<String>,
+    /// Let each client set an option for its own session only, without
+    /// affecting other clients, like Skill Level. Can be given multiple
+    /// times.
+    #[clap(long = "overlay-option")]
+    overlay_options: Vec<String>,
+    /// Quit the engine after this many seconds without a session using it.
+    /// It is restarted on demand. 0 disables.
+    #[clap(long, default_value_t = 0)]
+    idle_timeout: u64,
+    /// Stop a search if the engine prints nothing for this many seconds, and
+    /// give up if it still stays silent. 0 disables.
+    #[clap(long, default_value_t = 0)]
+    silent_timeout: u64,
+    /// Kind of engine: alpha_beta or neural_net. Neural network engines, like
+    /// Lc0, may stay silent while loading their network.
+    #[clap(long, default_value = "alpha_beta")]
+    engine_kind: EngineKind,
+    /// Format of engine input and output logs: plain or json.
+    #[clap(long, default_value = "plain")]
+    log_format: LogFormat,
+    /// Log the exact lines received from the engine at trace level, with
+    /// control characters escaped.
+    #[clap(long)]
+    log_raw_lines: bool,
+    /// Log only the number of options advertised by the engine at info
+    /// level, not each of them.
+    #[clap(long)]
+    quiet_options: bool,
+    /// Limit the address space of the engine process (MiB).
+    #[clap(long)]
+    max_engine_memory: Option<u64>,
+    /// Limit the CPU time of the engine process (seconds).
+    #[clap(long)]
+    max_engine_cpu_time: Option<u64>,
+    /// Retry starting the engine this many times after transient errors,
+    /// like a busy binary that is still being replaced.
+    #[clap(long, default_value_t = 0)]
+    spawn_retries: u32,
+    /// Stop restarting the engine after it crashed this many times within
+    /// --crash-window seconds, until --crash-cooldown seconds have passed.
+    /// 0 disables.
+    #[clap(long, default_value_t = 0)]
+    max_crashes: usize,
+    #[clap(long, default_value_t = 60)]
+    crash_window: u64,
+    #[clap(long, default_value_t = 300)]
+    crash_cooldown: u64,
+    /// Quit the engine if its resident memory exceeds this limit (MiB),
+    /// checked every --memory-check-interval seconds. It is restarted on
+    /// demand. Only supported on Linux.
+    #[clap(long)]
+    max_engine_rss: Option<u64>,
+    #[clap(long, default_value_t = 5)]
+    memory_check_interval: u64,
+    /// Run a short search after starting the engine, so that the first
+    /// request is fast.
+    #[clap(long)]
+    warmup: bool,
+    /// Clamp out of range values for numeric options, instead of rejecting
+    /// them.
+    #[clap(long)]
+    clamp_options: bool,
+    /// Pass this flag if the engine does not support ucinewgame.
+    #[clap(long)]
+    no_ucinewgame: bool,
+    /// Pass this flag if the engine expects stop even when it is not
+    /// searching.
+    #[clap(long)]
+    always_send_stop: bool,
+    /// Stop a running search when the client starts a new one, instead of
+    /// rejecting it.
+    #[clap(long)]
+    auto_stop_on_go: bool,
     /// Provide file with secret token to use instead of a random one.
     #[clap(long)]
     secret_file: Option<PathBuf>,
+    /// Enable the local control endpoints (/control/status, /control/stop
+    /// and /control/sessions/<session>/cancel), requiring this bearer token.
+    #[clap(long)]
+    control_token: Option<String>,
     /// Promise that the selected engine is a recent official Stockfish
     /// release.
     #[clap(long, hide = true)]
@@ -100,13 +248,13 @@ pub struct EngineOpts {
     #[clap(long, display_order = 6)]
     engine_x86_64_sse3_popcnt: Option<PathBuf>,
     /// Or else, the UCI engine executable to use.
-    #[clap(long, display_order = 7)]
-    engine: PathBuf,
+    #[clap(long, display_order = 7, required_unless_present = "config")]
+    engine: Option<PathBuf>,
 }
 
 impl EngineOpts {
     #[cfg(target_arch = "x86_64")]
-    fn best(self) -> PathBuf {
+    fn best(self) -> Option<PathBuf> {
         self.engine_x86_64_vnni512
             .filter(|_| {
                 is_x86_feature_detected!("avx512dq")
@@ -122,12 +270,10 @@ impl EngineOpts {
                     // long time. The Zen 3 family (0x19) is the first to
                     // implement it in hardware.
                     let cpuid = raw_cpuid::CpuId::new();
-                    cpuid
-                        .get_vendor_info()
-                        .map_or(true, |v| v.as_str() != "AuthenticAMD")
+                    !matches!(cpuid.get_vendor_info(), Some(v) if v.as_str() == "AuthenticAMD")
                         || cpuid
                             .get_feature_info()
-                            .map_or(false, |f| f.family_id() >= 0x19)
+                            .is_some_and(|f| f.family_id() >= 0x19)
                 }
             })
             .or(self.engine_x86_64_avx2)
@@ -138,11 +284,11 @@ impl EngineOpts {
             .filter(|_| is_x86_feature_detected!("ssse3"))
             .or(self.engine_x86_64_sse3_popcnt)
             .filter(|_| is_x86_feature_detected!("sse3") && is_x86_feature_detected!("popcnt"))
-            .unwrap_or(self.engine)
+            .or(self.engine)
     }
 
     #[cfg(not(target_arch = "x86_64"))]
-    fn best(self) -> PathBuf {
+    fn best(self) -> Option<PathBuf> {
         self.engine
     }
 }
@@ -168,7 +314,7 @@ impl ExternalWorkerOpts {
     pub fn registration_url(&self) -> String {
         format!(
             "https://lichess.org/analysis/external?{}",
-            serde_urlencoded::to_string(&self).expect("serialize spec"),
+            serde_urlencoded::to_string(self).expect("serialize spec"),
         )
     }
 }
@@ -231,33 +377,122 @@ pub async fn make_server(
             err
         })?;
 
-    let mut options: HashMap<UciOptionName, String> = HashMap::new();
+    let (path, mut params, mut options) = match opts.config {
+        Some(ref path) => {
+            let config = Config::from_path(path).map_err(|err| {
+                log::error!("Could not load config file {path:?}: {err}");
+                err
+            })?;
+            let options = config.option_overrides();
+            (config.engine.clone(), config.parameters(), options)
+        }
+        None => (
+            opts.engine.best().expect("engine or config required"),
+            EngineParameters {
+                args: opts.engine_args,
+                sandbox: (!opts.sandbox.is_empty()).then_some(opts.sandbox),
+                kind: opts.engine_kind,
+                max_threads: opts.max_threads.unwrap_or(u32::MAX),
+                max_hash: opts.max_hash.unwrap_or(u32::MAX),
+                max_hash_fraction: opts.max_hash_fraction,
+                blocked_options: opts.block_options.into_iter().map(UciOptionName).collect(),
+                overlay_options: opts
+                    .overlay_options
+                    .into_iter()
+                    .map(UciOptionName)
+                    .collect(),
+                on_out_of_range: if opts.clamp_options {
+                    OnOutOfRange::Clamp
+                } else {
+                    OnOutOfRange::Reject
+                },
+                unknown_option_policy: UnknownOptionPolicy::Ignore,
+                synthetic_bestmove_on_exit: false,
+                supports_ucinewgame: !opts.no_ucinewgame,
+                always_send_stop: opts.always_send_stop,
+                auto_stop_on_go: opts.auto_stop_on_go,
+                timeouts: Timeouts {
+                    silent: (opts.silent_timeout > 0)
+                        .then(|| Duration::from_secs(opts.silent_timeout)),
+                    ..Timeouts::default()
+                },
+                log_format: opts.log_format,
+                log_raw_lines: opts.log_raw_lines,
+                quiet_options: opts.quiet_options,
+                max_pv_length: opts.max_pv_length,
+                max_nodes: opts.max_nodes,
+                ready_before_go: opts.ready_before_go,
+                sync_after_bestmove: opts.sync_after_bestmove,
+                max_search_time: (opts.max_search_time > 0)
+                    .then(|| Duration::from_secs(opts.max_search_time)),
+                min_search_time: (opts.min_search_time > 0)
+                    .then(|| Duration::from_millis(opts.min_search_time)),
+                search_limit: (opts.max_searches > 0)
+                    .then(|| SearchLimit::new(opts.max_searches, opts.search_limit_policy)),
+                position_transform: None,
+                max_position_moves: opts.max_position_moves,
+                reject_illegal_bestmove: false,
+                rlimits: (opts.max_engine_memory.is_some() || opts.max_engine_cpu_time.is_some())
+                    .then(|| RLimits {
+                        address_space: opts.max_engine_memory.map(|mib| mib * 1024 * 1024),
+                        cpu_time: opts.max_engine_cpu_time.map(Duration::from_secs),
+                    }),
+                spawn_retry: (opts.spawn_retries > 0).then(|| SpawnRetry {
+                    attempts: opts.spawn_retries,
+                    ..SpawnRetry::default()
+                }),
+                crash_breaker: (opts.max_crashes > 0).then(|| CrashBreaker {
+                    max_crashes: opts.max_crashes,
+                    window: Duration::from_secs(opts.crash_window),
+                    cooldown: Duration::from_secs(opts.crash_cooldown),
+                }),
+                memory_guard: opts.max_engine_rss.map(|mib| MemoryGuard {
+                    max_resident: mib * 1024 * 1024,
+                    interval: Duration::from_secs(opts.memory_check_interval),
+                }),
+                recorder: None,
+                warmup: opts.warmup,
+            },
+            HashMap::new(),
+        ),
+    };
+    params.max_threads = min(
+        params.max_threads,
+        u32::try_from(usize::from(
+            thread::available_parallelism().expect("available threads"),
+        ))
+        .unwrap_or(u32::MAX),
+    );
+    params.max_hash = min(
+        params.max_hash,
+        u32::try_from(available_memory()).unwrap_or(u32::MAX),
+    );
     if let Some(eval_file) = opts.eval_file {
-        options.insert(UciOptionName("EvalFile".to_string()), eval_file);
+        options.insert(
+            UciOptionName("EvalFile".to_string()),
+            if opts.require_eval_file {
+                OptionOverride::Required(eval_file)
+            } else {
+                OptionOverride::Value(eval_file)
+            },
+        );
     }
-    let engine = Engine::new(
-        opts.engine.best(),
-        EngineParameters {
-            max_threads: min(
-                opts.max_threads.unwrap_or(u32::MAX),
-                u32::try_from(usize::from(
-                    thread::available_parallelism().expect("available threads"),
-                ))
-                .unwrap_or(u32::MAX),
-            ),
-            max_hash: min(
-                opts.max_hash.unwrap_or(u32::MAX),
-                u32::try_from(available_memory()).unwrap_or(u32::MAX),
-            ),
-        },
-        options,
-    )
-    .await
-    .map_err(|err| {
+    let engine = Engine::new(path, params, options).await.map_err(|err| {
         log::error!("Could not start engine: {err}");
         err
     })?;
 
+    let capabilities = engine.capabilities();
+    if !capabilities.threads {
+        log::info!("Engine has no Threads option, registering as single-threaded");
+    }
+    if !capabilities.hash {
+        log::info!(
+            "Engine has no Hash option, registering with {} MiB",
+            engine.max_hash()
+        );
+    }
+
     let spec = ExternalWorkerOpts {
         url: format!(
             "{}://{}/socket",
@@ -268,14 +503,21 @@ pub async fn make_server(
         secret: secret.clone(),
         max_threads: engine.max_threads(),
         max_hash: engine.max_hash(),
-        variants: engine.variants().to_vec(),
+        variants: engine.normalized_variants(),
         name: engine.name().unwrap_or("remote-uci").to_owned(),
         official_stockfish: opts.promise_official_stockfish,
     };
 
     let engine = Arc::new(SharedEngine::new(engine));
 
-    let app = Router::new()
+    if opts.idle_timeout > 0 {
+        tokio::spawn(ws::reap_idle(
+            Arc::clone(&engine),
+            Duration::from_secs(opts.idle_timeout),
+        ));
+    }
+
+    let mut app = Router::new()
         .route(
             "/",
             get({
@@ -287,11 +529,44 @@ pub async fn make_server(
             "/socket",
             get({
                 let engine = Arc::clone(&engine);
-                let secret = secret;
-                move |params, socket| ws::handler(engine, secret, params, socket)
+                let info_interval = Duration::from_millis(opts.info_interval);
+                move |params, socket| ws::handler(engine, secret, info_interval, params, socket)
             }),
         );
 
+    if let Some(token) = opts.control_token {
+        if token.len() < 8 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "control token too short").into(),
+            );
+        }
+        let token = Secret(token);
+        app = app
+            .route(
+                "/control/status",
+                get({
+                    let engine = Arc::clone(&engine);
+                    let token = token.clone();
+                    move |headers| control::status(engine, token, headers)
+                }),
+            )
+            .route(
+                "/control/stop",
+                post({
+                    let engine = Arc::clone(&engine);
+                    let token = token.clone();
+                    move |headers| control::stop(engine, token, headers)
+                }),
+            )
+            .route(
+                "/control/sessions/:session/cancel",
+                post({
+                    let engine = Arc::clone(&engine);
+                    move |headers, path| control::cancel(engine, token, headers, path)
+                }),
+            );
+    }
+
     Ok((
         spec,
         axum::Server::from_tcp(listener)?.serve(app.into_make_service()),
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::uci::{UciIn, UciOptionName};
+
+/// A named bundle of engine options and default search limits, for example
+/// `fast`, `deep` or `teaching`. Applied with
+/// [`crate::engine::Engine::apply_profile()`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    pub threads: Option<i64>,
+    pub hash: Option<i64>,
+    pub multipv: Option<i64>,
+    pub analyse_mode: Option<bool>,
+    pub skill_level: Option<i64>,
+    /// Also enables `UCI_LimitStrength`.
+    pub elo: Option<i64>,
+    pub depth: Option<u32>,
+    pub nodes: Option<u64>,
+    /// Milliseconds.
+    pub movetime: Option<u64>,
+}
+
+impl Profile {
+    /// The engine options set by this profile.
+    pub fn options(&self) -> Vec<(UciOptionName, String)> {
+        let mut options = Vec::new();
+        let mut push = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                options.push((UciOptionName(name.to_owned()), value));
+            }
+        };
+        push("Threads", self.threads.map(|v| v.to_string()));
+        push("Hash", self.hash.map(|v| v.to_string()));
+        push("MultiPV", self.multipv.map(|v| v.to_string()));
+        push("UCI_AnalyseMode", self.analyse_mode.map(|v| v.to_string()));
+        push("Skill Level", self.skill_level.map(|v| v.to_string()));
+        push("UCI_LimitStrength", self.elo.map(|_| "true".to_owned()));
+        push("UCI_Elo", self.elo.map(|v| v.to_string()));
+        options
+    }
+
+    /// A `go` command with the default search limits of this profile.
+    /// Searches infinitely if the profile has no limits.
+    pub fn go(&self) -> UciIn {
+        UciIn::Go {
+            searchmoves: None,
+            ponder: false,
+            wtime: None,
+            btime: None,
+            winc: None,
+            binc: None,
+            movestogo: None,
+            depth: self.depth,
+            nodes: self.nodes,
+            mate: None,
+            movetime: self.movetime.map(Duration::from_millis),
+            infinite: self.depth.is_none() && self.nodes.is_none() && self.movetime.is_none(),
+            perft: None,
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, uci::Uci};
+
+use crate::uci::{Eval, ProtocolError, UciIn, UciOut};
+
+/// Analysis request received from Lichess.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Work {
+    pub session_id: String,
+    pub threads: u32,
+    pub hash: u32,
+    #[serde(default)]
+    pub infinite: bool,
+    pub multi_pv: u32,
+    pub variant: String,
+    pub initial_fen: String,
+    pub moves: Vec<String>,
+}
+
+impl Work {
+    pub fn position(&self) -> Result<UciIn, ProtocolError> {
+        Ok(UciIn::Position {
+            fen: Some(self.initial_fen.parse::<Fen>()?),
+            moves: self
+                .moves
+                .iter()
+                .map(|m| m.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Message sent to Lichess.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ProviderMessage {
+    Analysis(AnalysisOutput),
+    Bestmove {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bestmove: Option<String>,
+    },
+    /// Free-form engine chatter from `info string`, for example
+    /// `NNUE evaluation using nn-6877cd24400e.nnue enabled`.
+    Comment {
+        text: String,
+    },
+    Keepalive,
+}
+
+impl ProviderMessage {
+    /// Maps engine output to the message that should be forwarded, if any.
+    /// This is the only place that decides which engine output reaches
+    /// Lichess.
+    pub fn from_uci(command: &UciOut) -> Option<ProviderMessage> {
+        match command {
+            UciOut::Bestmove { m, .. } => Some(ProviderMessage::Bestmove {
+                bestmove: m.as_ref().map(Uci::to_string),
+            }),
+            UciOut::Info {
+                string: Some(string),
+                score: None,
+                pv: None,
+                ..
+            } => Some(ProviderMessage::Comment {
+                text: string.clone(),
+            }),
+            _ => AnalysisOutput::from_uci(command).map(ProviderMessage::Analysis),
+        }
+    }
+
+    /// Renders the message as the line that is sent over the websocket, in
+    /// the subset of UCI that the Lichess client understands.
+    pub fn to_line(&self) -> Option<String> {
+        match self {
+            ProviderMessage::Analysis(analysis) => Some(analysis.to_line()),
+            ProviderMessage::Bestmove { bestmove } => Some(format!(
+                "bestmove {}",
+                bestmove.as_deref().unwrap_or("(none)")
+            )),
+            ProviderMessage::Comment { text } => Some(format!("info string {text}")),
+            ProviderMessage::Keepalive => None,
+        }
+    }
+
+    /// Like [`ProviderMessage::to_line()`], but also forwards the
+    /// `info string` of analysis, as a separate comment line.
+    pub fn to_lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.to_line().into_iter().collect();
+        if let ProviderMessage::Analysis(AnalysisOutput {
+            string: Some(text), ..
+        }) = self
+        {
+            lines.push(format!("info string {text}"));
+        }
+        lines
+    }
+}
+
+/// A single line of analysis.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalysisOutput {
+    pub multi_pv: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seldepth: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<u64>,
+    /// Milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    pub score: ScoreOutput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bound: Option<BoundOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nps: Option<u64>,
+    pub pv: Vec<String>,
+    /// `info string` sent on the same line, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub string: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ScoreOutput {
+    Cp(i64),
+    Mate(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BoundOutput {
+    Lowerbound,
+    Upperbound,
+}
+
+impl AnalysisOutput {
+    /// Maps an `info` line with a score and principal variation. Other lines
+    /// are not analysis output. Also skipped are `mate 0`, and bounds for
+    /// the first principal variation, which Lichess would display as if
+    /// they were exact.
+    pub fn from_uci(command: &UciOut) -> Option<AnalysisOutput> {
+        match command {
+            UciOut::Info {
+                multipv,
+                depth,
+                seldepth,
+                time,
+                nodes,
+                score: Some(score),
+                nps,
+                pv: Some(pv),
+                string,
+                ..
+            } if !pv.is_empty() => {
+                let multi_pv = multipv.map_or(1, |n| n.get());
+                let bound = if score.is_lowerbound() {
+                    Some(BoundOutput::Lowerbound)
+                } else if score.is_upperbound() {
+                    Some(BoundOutput::Upperbound)
+                } else {
+                    None
+                };
+                if *score.eval() == Eval::Mate(0) || (multi_pv == 1 && bound.is_some()) {
+                    return None;
+                }
+                Some(AnalysisOutput {
+                    multi_pv,
+                    depth: *depth,
+                    seldepth: *seldepth,
+                    nodes: *nodes,
+                    time: time.map(|t| t.as_millis() as u64),
+                    score: match score.eval() {
+                        Eval::Cp(cp) => ScoreOutput::Cp(*cp),
+                        Eval::Mate(mate) => ScoreOutput::Mate(*mate),
+                    },
+                    bound,
+                    nps: *nps,
+                    pv: pv.iter().map(Uci::to_string).collect(),
+                    string: string.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Renders the analysis as an `info` line, with fields in the usual
+    /// order.
+    pub fn to_line(&self) -> String {
+        let mut line = String::from("info");
+        if let Some(depth) = self.depth {
+            line.push_str(&format!(" depth {depth}"));
+        }
+        if let Some(seldepth) = self.seldepth {
+            line.push_str(&format!(" seldepth {seldepth}"));
+        }
+        line.push_str(&format!(" multipv {} score", self.multi_pv));
+        match self.score {
+            ScoreOutput::Cp(cp) => line.push_str(&format!(" cp {cp}")),
+            ScoreOutput::Mate(mate) => line.push_str(&format!(" mate {mate}")),
+        }
+        match self.bound {
+            Some(BoundOutput::Lowerbound) => line.push_str(" lowerbound"),
+            Some(BoundOutput::Upperbound) => line.push_str(" upperbound"),
+            None => (),
+        }
+        if let Some(nodes) = self.nodes {
+            line.push_str(&format!(" nodes {nodes}"));
+        }
+        if let Some(nps) = self.nps {
+            line.push_str(&format!(" nps {nps}"));
+        }
+        if let Some(time) = self.time {
+            line.push_str(&format!(" time {time}"));
+        }
+        line.push_str(" pv");
+        for m in &self.pv {
+            line.push_str(&format!(" {m}"));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_work() -> Result<(), ProtocolError> {
+        let work: Work = serde_json::from_str(
+            r#"{
+                "sessionId": "abc",
+                "threads": 4,
+                "hash": 256,
+                "infinite": true,
+                "multiPv": 2,
+                "variant": "chess",
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": ["e2e4", "e7e5"]
+            }"#,
+        )
+        .expect("valid work");
+        assert_eq!(work.multi_pv, 2);
+        assert_eq!(
+            work.position()?.to_string(),
+            "position fen rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 moves e2e4 e7e5"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_output() -> Result<(), ProtocolError> {
+        let info = UciOut::from_line(
+            "info depth 20 seldepth 28 multipv 2 score cp -31 nodes 1000 time 20 pv e7e5 g1f3",
+        )?
+        .expect("info");
+        assert_eq!(
+            serde_json::to_string(&ProviderMessage::from_uci(&info)).expect("serialize"),
+            r#"{"type":"analysis","multiPv":2,"depth":20,"seldepth":28,"nodes":1000,"time":20,"score":{"cp":-31},"pv":["e7e5","g1f3"]}"#
+        );
+
+        let annotated =
+            UciOut::from_line("info depth 5 score cp 12 pv e2e4 string tb hit")?.expect("info");
+        let message = ProviderMessage::from_uci(&annotated);
+        assert_eq!(
+            serde_json::to_string(&message).expect("serialize"),
+            r#"{"type":"analysis","multiPv":1,"depth":5,"score":{"cp":12},"pv":["e2e4"],"string":"tb hit"}"#
+        );
+        assert_eq!(
+            message.map(|message| message.to_lines()),
+            Some(vec![
+                "info depth 5 multipv 1 score cp 12 pv e2e4".to_owned(),
+                "info string tb hit".to_owned()
+            ])
+        );
+
+        let noise = UciOut::from_line("info depth 20 currmove e2e4")?.expect("info");
+        assert_eq!(ProviderMessage::from_uci(&noise), None);
+
+        let bestmove = UciOut::from_line("bestmove e2e4 ponder e7e5")?.expect("bestmove");
+        assert_eq!(
+            serde_json::to_string(&ProviderMessage::from_uci(&bestmove)).expect("serialize"),
+            r#"{"type":"bestmove","bestmove":"e2e4"}"#
+        );
+
+        let comment = UciOut::from_line("info string using 4 threads")?.expect("info");
+        assert_eq!(
+            serde_json::to_string(&ProviderMessage::from_uci(&comment)).expect("serialize"),
+            r#"{"type":"comment","text":"using 4 threads"}"#
+        );
+        assert_eq!(
+            ProviderMessage::from_uci(&comment).and_then(|message| message.to_line()),
+            Some("info string using 4 threads".to_owned())
+        );
+        Ok(())
+    }
+}
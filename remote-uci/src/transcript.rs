@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::engine::{Engine, EngineParameters, ProtocolState};
+
+/// Records the commands sent to an engine and every line of its output,
+/// with timestamps, as a transcript that can be replayed with [`replay()`].
+/// Install it with [`EngineParameters::recorder`] to record from the
+/// handshake on. Clones write to the same transcript.
+///
+/// Each line of the transcript is the number of milliseconds since the
+/// recorder was created, the direction (`<<` for commands, `>>` for
+/// output, like in the logs), and the line itself:
+///
+/// ```text
+/// 0 << uci
+/// 3 >> id name Stockfish 15
+/// ```
+#[derive(Clone)]
+pub struct SessionRecorder {
+    started: Instant,
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl SessionRecorder {
+    pub fn new<W>(sink: W) -> SessionRecorder
+    where
+        W: Write + Send + 'static,
+    {
+        SessionRecorder {
+            started: Instant::now(),
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: TranscriptDirection, line: &str) {
+        let entry = TranscriptEntry {
+            at: self.started.elapsed(),
+            direction,
+            line: line.to_owned(),
+        };
+        let mut sink = self.sink.lock().expect("recorder poisoned");
+        if let Err(err) = writeln!(sink, "{entry}").and_then(|()| sink.flush()) {
+            log::warn!("Failed to record transcript: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for SessionRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRecorder")
+            .field("started", &self.started)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TranscriptDirection {
+    /// Command sent to the engine.
+    In,
+    /// Line of output received from the engine.
+    Out,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TranscriptEntry {
+    pub at: Duration,
+    pub direction: TranscriptDirection,
+    pub line: String,
+}
+
+impl fmt::Display for TranscriptEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.at.as_millis(),
+            match self.direction {
+                TranscriptDirection::In => "<<",
+                TranscriptDirection::Out => ">>",
+            },
+            self.line
+        )
+    }
+}
+
+/// A transcript written by a [`SessionRecorder`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Transcript {
+    pub entries: Vec<TranscriptEntry>,
+}
+
+impl FromStr for Transcript {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Transcript> {
+        let invalid = |n: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid transcript line {}", n + 1),
+            )
+        };
+        let mut entries = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let (at, rest) = line.split_once(' ').ok_or_else(|| invalid(n))?;
+            let (direction, line) = match rest.split_once(' ') {
+                Some((direction, line)) => (direction, line),
+                None => (rest, ""),
+            };
+            entries.push(TranscriptEntry {
+                at: Duration::from_millis(at.parse().map_err(|_| invalid(n))?),
+                direction: match direction {
+                    "<<" => TranscriptDirection::In,
+                    ">>" => TranscriptDirection::Out,
+                    _ => return Err(invalid(n)),
+                },
+                line: line.to_owned(),
+            });
+        }
+        Ok(Transcript { entries })
+    }
+}
+
+/// Replays `transcript` through the protocol state machine of an engine with
+/// `params`, and checks the state after each entry against `expected`.
+/// For golden file tests, to verify that the handling of a recorded session
+/// is unchanged. Fails with [`io::ErrorKind::InvalidData`] at the first
+/// difference, see [`Engine::replay()`].
+pub async fn replay(
+    transcript: &Transcript,
+    params: EngineParameters,
+    expected: &[ProtocolState],
+) -> io::Result<()> {
+    let states = Engine::replay(transcript, params).await?;
+    if let Some((n, (state, expected))) = states
+        .iter()
+        .zip(expected)
+        .enumerate()
+        .find(|(_, (state, expected))| state != expected)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "after {}: expected {:?}, got {:?}",
+                transcript.entries[n], expected, state
+            ),
+        ));
+    }
+    if states.len() != expected.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} states, got {}", expected.len(), states.len()),
+        ));
+    }
+    Ok(())
+}
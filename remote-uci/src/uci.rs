@@ -7,12 +7,21 @@ use std::{
 };
 
 use memchr::{memchr2, memchr2_iter};
+use serde::Serialize;
 use shakmaty::{
     fen::{Fen, ParseFenError},
     uci::{ParseUciError, Uci},
+    Color,
 };
 use thiserror::Error;
 
+use crate::protocol::ProviderMessage;
+
+/// A move in UCI notation, as used in `position`, `go searchmoves` and
+/// `bestmove`: a normal move like `e2e4` with an optional promotion
+/// (`e7e8q`), a drop like `P@e4` for crazyhouse, or the null move `0000`.
+pub use shakmaty::uci::Uci as UciMove;
+
 #[derive(Clone, Debug, Eq)]
 pub struct UciOptionName(pub String);
 
@@ -31,6 +40,7 @@ impl UciOptionName {
             || *self == "UCI_Chess960"
             || *self == "UCI_Variant"
             || *self == "Analysis Contempt"
+            || *self == "Clear Hash"
     }
 }
 
@@ -105,7 +115,76 @@ impl UciOption {
     }
 }
 
+/// Public description of an option, for example to present the option table
+/// in a user interface.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OptionDescriptor {
+    pub name: String,
+    pub kind: OptionKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vars: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionKind {
+    Check,
+    Spin,
+    Combo,
+    Button,
+    String,
+}
+
 impl UciOption {
+    pub fn descriptor(&self, name: &UciOptionName) -> OptionDescriptor {
+        let mut descriptor = OptionDescriptor {
+            name: name.0.clone(),
+            kind: OptionKind::Button,
+            default: None,
+            min: None,
+            max: None,
+            vars: Vec::new(),
+        };
+        match self {
+            UciOption::Check { default } => {
+                descriptor.kind = OptionKind::Check;
+                descriptor.default = Some(default.to_string());
+            }
+            UciOption::Spin { default, min, max } => {
+                descriptor.kind = OptionKind::Spin;
+                descriptor.default = Some(default.to_string());
+                descriptor.min = Some(*min);
+                descriptor.max = Some(*max);
+            }
+            UciOption::Combo { default, var } => {
+                descriptor.kind = OptionKind::Combo;
+                descriptor.default = Some(default.clone());
+                descriptor.vars = var.clone();
+            }
+            UciOption::Button => (),
+            UciOption::String { default } => {
+                descriptor.kind = OptionKind::String;
+                descriptor.default = Some(default.clone());
+            }
+        }
+        descriptor
+    }
+}
+
+impl UciOption {
+    pub fn min(&self) -> Option<i64> {
+        match self {
+            UciOption::Spin { min, .. } => Some(*min),
+            _ => None,
+        }
+    }
+
     pub fn max(&self) -> Option<i64> {
         match self {
             UciOption::Spin { max, .. } => Some(*max),
@@ -120,8 +199,21 @@ impl UciOption {
         }
     }
 
+    /// Clamps `value` to the range of a spin option. Other options, and spin
+    /// options with an empty range (a malformed `min` above `max`), return
+    /// `value` unchanged.
+    pub fn clamp(&self, value: i64) -> i64 {
+        match *self {
+            UciOption::Spin { min, max, .. } if min <= max => value.clamp(min, max),
+            _ => value,
+        }
+    }
+
     pub fn limit_max(&mut self, limit: i64) {
         if let UciOption::Spin { min, max, default } = self {
+            if *min > *max {
+                return;
+            }
             *max = limit.clamp(*min, *max);
             *default = (*default).clamp(*min, *max);
         }
@@ -183,14 +275,29 @@ pub enum UciIn {
         mate: Option<u32>,
         movetime: Option<Duration>,
         infinite: bool,
+        /// Nonstandard: count the leaf nodes of the move tree to this depth,
+        /// instead of searching. Answered with [`UciOut::Perft`] rather than
+        /// `bestmove`.
+        perft: Option<u32>,
     },
     Stop,
     Ponderhit,
+    Quit,
+    /// Nonstandard: print the static evaluation of the current position.
+    /// Stockfish answers with a breakdown, ending with [`UciOut::Eval`]
+    /// summary lines.
+    #[cfg(feature = "stockfish")]
+    Eval,
+    /// Nonstandard: run the built-in benchmark of Stockfish, a fixed series
+    /// of searches. Ends with [`UciOut::Bench`] summary lines, if printed
+    /// to standard output at all.
+    #[cfg(feature = "stockfish")]
+    Bench,
 }
 
 impl UciIn {
     pub fn from_line(s: &str) -> Result<Option<UciIn>, ProtocolError> {
-        Parser::new(s)?.parse_in()
+        Parser::new(s, ParseMode::Strict)?.parse_in()
     }
 }
 
@@ -233,6 +340,7 @@ impl fmt::Display for UciIn {
                 mate,
                 movetime,
                 infinite,
+                perft,
             } => {
                 f.write_str("go")?;
                 if let Some(searchmoves) = searchmoves {
@@ -274,10 +382,18 @@ impl fmt::Display for UciIn {
                 if *infinite {
                     f.write_str(" infinite")?;
                 }
+                if let Some(perft) = perft {
+                    write!(f, " perft {perft}")?;
+                }
                 Ok(())
             }
             UciIn::Stop => f.write_str("stop"),
             UciIn::Ponderhit => f.write_str("ponderhit"),
+            UciIn::Quit => f.write_str("quit"),
+            #[cfg(feature = "stockfish")]
+            UciIn::Eval => f.write_str("eval"),
+            #[cfg(feature = "stockfish")]
+            UciIn::Bench => f.write_str("bench"),
         }
     }
 }
@@ -302,6 +418,56 @@ pub struct Score {
     eval: Eval,
     lowerbound: bool,
     upperbound: bool,
+    wdl: Option<Wdl>,
+}
+
+/// Expected outcome in permille, as reported with `UCI_ShowWDL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wdl {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Score {
+    pub fn eval(&self) -> &Eval {
+        &self.eval
+    }
+
+    pub fn is_lowerbound(&self) -> bool {
+        self.lowerbound
+    }
+
+    pub fn is_upperbound(&self) -> bool {
+        self.upperbound
+    }
+
+    pub fn wdl(&self) -> Option<Wdl> {
+        self.wdl
+    }
+
+    /// Converts the score from the point of view of the side to move, as
+    /// reported by the engine, to the point of view of white, like in an
+    /// evaluation graph. Bounds and win/loss chances are swapped
+    /// accordingly.
+    pub fn white_pov(&self, side_to_move: Color) -> Score {
+        match side_to_move {
+            Color::White => self.clone(),
+            Color::Black => Score {
+                eval: match self.eval {
+                    Eval::Cp(cp) => Eval::Cp(-cp),
+                    Eval::Mate(mate) => Eval::Mate(-mate),
+                },
+                lowerbound: self.upperbound,
+                upperbound: self.lowerbound,
+                wdl: self.wdl.map(|wdl| Wdl {
+                    wins: wdl.losses,
+                    draws: wdl.draws,
+                    losses: wdl.wins,
+                }),
+            },
+        }
+    }
 }
 
 impl fmt::Display for Score {
@@ -313,6 +479,14 @@ impl fmt::Display for Score {
         if self.upperbound {
             f.write_str(" upperbound")?;
         }
+        if let Some(Wdl {
+            wins,
+            draws,
+            losses,
+        }) = self.wdl
+        {
+            write!(f, " wdl {wins} {draws} {losses}")?;
+        }
         Ok(())
     }
 }
@@ -342,8 +516,11 @@ pub enum UciOut {
         tbhits: Option<u64>,
         sbhits: Option<u64>,
         cpuload: Option<u32>,
+        movesleft: Option<u32>,
         refutation: HashMap<Uci, Vec<Uci>>,
         currline: HashMap<u32, Vec<Uci>>,
+        /// Truncated before the first malformed move, if any, unless parsed
+        /// in [`ParseMode::Strict`].
         pv: Option<Vec<Uci>>,
         string: Option<String>,
     },
@@ -351,11 +528,112 @@ pub enum UciOut {
         name: UciOptionName,
         option: UciOption,
     },
+    /// Nonstandard: number of leaf nodes below a single move, as printed
+    /// before the total by `go perft`, like `e2e4: 20`.
+    PerftMove {
+        m: Uci,
+        nodes: u64,
+    },
+    /// Nonstandard: total number of leaf nodes counted by `go perft`, from
+    /// `Nodes searched: 8902`. Ends the `go` like `bestmove`.
+    Perft {
+        nodes: u64,
+    },
+    /// Nonstandard: summary line of the static evaluation printed by
+    /// Stockfish after `eval`, like `NNUE evaluation +0.10 (white side)`.
+    /// Centipawns from the point of view of white, or `None` if there is no
+    /// evaluation, for example when in check.
+    #[cfg(feature = "stockfish")]
+    Eval {
+        kind: EvalKind,
+        cp: Option<i64>,
+    },
+    /// Nonstandard: summary line printed by Stockfish after `bench`, like
+    /// `Nodes/second    : 1234567`.
+    #[cfg(feature = "stockfish")]
+    Bench {
+        stat: BenchStat,
+        value: u64,
+    },
+}
+
+/// Part of the static evaluation reported in [`UciOut::Eval`].
+#[cfg(feature = "stockfish")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvalKind {
+    /// Handcrafted evaluation, in older versions.
+    Classical,
+    Nnue,
+    /// Blend of the above, as used in search.
+    Final,
+}
+
+#[cfg(feature = "stockfish")]
+impl fmt::Display for EvalKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvalKind::Classical => "Classical",
+            EvalKind::Nnue => "NNUE",
+            EvalKind::Final => "Final",
+        })
+    }
+}
+
+/// Figure reported in [`UciOut::Bench`].
+#[cfg(feature = "stockfish")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BenchStat {
+    /// Milliseconds.
+    TotalTime,
+    Nodes,
+    Nps,
+}
+
+#[cfg(feature = "stockfish")]
+impl fmt::Display for BenchStat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            BenchStat::TotalTime => "Total time (ms)",
+            BenchStat::Nodes => "Nodes searched",
+            BenchStat::Nps => "Nodes/second",
+        })
+    }
+}
+
+/// How to deal with tokens that are not part of the UCI specification.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseMode {
+    /// Fail with [`ProtocolError::UnexpectedToken`], to check engines for
+    /// conformance.
+    Strict,
+    /// Skip unknown `info` keys (nonstandard extensions like `ebf`) and
+    /// trailing tokens. Used by [`crate::engine::Engine::recv()`].
+    Lenient,
 }
 
 impl UciOut {
+    /// Parses a line of engine output in [`ParseMode::Lenient`].
     pub fn from_line(s: &str) -> Result<Option<UciOut>, ProtocolError> {
-        Parser::new(s)?.parse_out()
+        UciOut::from_line_with(s, ParseMode::Lenient)
+    }
+
+    /// Parses a line of engine output. Lines that are not UCI commands at
+    /// all, like banners, are `None` in both modes.
+    pub fn from_line_with(s: &str, mode: ParseMode) -> Result<Option<UciOut>, ProtocolError> {
+        Parser::new(s, mode)?.parse_out()
+    }
+
+    /// Renders the line in the subset of UCI that the Lichess analysis
+    /// client understands, with fields in the usual order, or `None` for
+    /// lines it does not want. That is anything but `bestmove` and `info`
+    /// with a score and principal variation. Also skipped are `mate 0`, and
+    /// bounds for the first principal variation. See
+    /// [`ProviderMessage::from_uci()`] for the mapping.
+    pub fn to_lichess_line(&self) -> Option<String> {
+        match ProviderMessage::from_uci(self)? {
+            ProviderMessage::Comment { .. } => None,
+            message => message.to_line(),
+        }
     }
 }
 
@@ -390,6 +668,7 @@ impl fmt::Display for UciOut {
                 tbhits,
                 sbhits,
                 cpuload,
+                movesleft,
                 refutation,
                 currline,
                 pv,
@@ -435,6 +714,9 @@ impl fmt::Display for UciOut {
                 if let Some(cpuload) = cpuload {
                     write!(f, " cpuload {cpuload}")?;
                 }
+                if let Some(movesleft) = movesleft {
+                    write!(f, " movesleft {movesleft}")?;
+                }
                 for (refuted, refuted_by) in refutation {
                     write!(f, " refutation {refuted}")?;
                     for m in refuted_by {
@@ -459,6 +741,20 @@ impl fmt::Display for UciOut {
                 Ok(())
             }
             UciOut::Option { name, option } => write!(f, "option name {name} {option}"),
+            UciOut::PerftMove { m, nodes } => write!(f, "{m}: {nodes}"),
+            UciOut::Perft { nodes } => write!(f, "Nodes searched: {nodes}"),
+            #[cfg(feature = "stockfish")]
+            UciOut::Eval { kind, cp: Some(cp) } => write!(
+                f,
+                "{kind} evaluation {}{}.{:02} (white side)",
+                if *cp < 0 { '-' } else { '+' },
+                cp.unsigned_abs() / 100,
+                cp.unsigned_abs() % 100
+            ),
+            #[cfg(feature = "stockfish")]
+            UciOut::Eval { kind, cp: None } => write!(f, "{kind} evaluation: none (in check)"),
+            #[cfg(feature = "stockfish")]
+            UciOut::Bench { stat, value } => write!(f, "{stat:<16}: {value}"),
         }
     }
 }
@@ -483,8 +779,30 @@ pub enum ProtocolError {
     InvalidOptionValue,
 }
 
+const INFO_KEYS: [&str; 18] = [
+    "multipv",
+    "depth",
+    "seldepth",
+    "time",
+    "nodes",
+    "score",
+    "currmove",
+    "currmovenumber",
+    "hashfull",
+    "nps",
+    "tbhits",
+    "sbhits",
+    "cpuload",
+    "movesleft",
+    "refutation",
+    "currline",
+    "pv",
+    "string",
+];
+
 struct Parser<'a> {
     s: &'a str,
+    mode: ParseMode,
 }
 
 impl<'a> Iterator for Parser<'a> {
@@ -498,10 +816,10 @@ impl<'a> Iterator for Parser<'a> {
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(s: &str) -> Result<Parser<'_>, ProtocolError> {
+    pub fn new(s: &str, mode: ParseMode) -> Result<Parser<'_>, ProtocolError> {
         match memchr2(b'\r', b'\n', s.as_bytes()) {
             Some(_) => Err(ProtocolError::UnexpectedLineBreak),
-            None => Ok(Parser { s }),
+            None => Ok(Parser { s, mode }),
         }
     }
 
@@ -526,6 +844,15 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// Like [`Parser::end()`], but ignores trailing tokens in
+    /// [`ParseMode::Lenient`].
+    fn end_strict(&self) -> Result<(), ProtocolError> {
+        match self.mode {
+            ParseMode::Strict => self.end(),
+            ParseMode::Lenient => Ok(()),
+        }
+    }
+
     fn parse_setoption(&mut self) -> Result<UciIn, ProtocolError> {
         Ok(match self.next() {
             Some("name") => UciIn::Setoption {
@@ -593,6 +920,25 @@ impl<'a> Parser<'a> {
         moves
     }
 
+    /// Like [`Parser::parse_moves()`], but also consumes anything after a
+    /// malformed move, up to the next info key. The principal variation is
+    /// truncated before the bad token, or in [`ParseMode::Strict`], the
+    /// line is rejected.
+    fn parse_pv(&mut self) -> Result<Vec<Uci>, ProtocolError> {
+        let moves = self.parse_moves();
+        if let Some(token) = self.peek().filter(|token| !INFO_KEYS.contains(token)) {
+            if self.mode == ParseMode::Strict {
+                return Err(token
+                    .parse::<Uci>()
+                    .err()
+                    .map_or(ProtocolError::UnexpectedToken, ProtocolError::InvalidMove));
+            }
+            log::debug!("truncating pv at malformed move: {}", token);
+            self.until(|token| INFO_KEYS.contains(&token));
+        }
+        Ok(moves)
+    }
+
     fn parse_go(&mut self) -> Result<UciIn, ProtocolError> {
         let mut searchmoves = None;
         let mut ponder = false;
@@ -606,6 +952,7 @@ impl<'a> Parser<'a> {
         let mut mate = None;
         let mut movetime = None;
         let mut infinite = false;
+        let mut perft = None;
         loop {
             match self.next() {
                 Some("ponder") => ponder = true,
@@ -644,6 +991,13 @@ impl<'a> Parser<'a> {
                 Some("winc") => winc = Some(self.parse_millis()?),
                 Some("binc") => binc = Some(self.parse_millis()?),
                 Some("searchmoves") => searchmoves = Some(self.parse_moves()),
+                Some("perft") => {
+                    perft = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
                 Some(_) => return Err(ProtocolError::UnexpectedToken),
                 None => break,
             }
@@ -661,6 +1015,7 @@ impl<'a> Parser<'a> {
             mate,
             movetime,
             infinite,
+            perft,
         })
     }
 
@@ -686,6 +1041,20 @@ impl<'a> Parser<'a> {
                 self.end()?;
                 UciIn::Ponderhit
             }
+            Some("quit") => {
+                self.end()?;
+                UciIn::Quit
+            }
+            #[cfg(feature = "stockfish")]
+            Some("eval") => {
+                self.end()?;
+                UciIn::Eval
+            }
+            #[cfg(feature = "stockfish")]
+            Some("bench") => {
+                self.end()?;
+                UciIn::Bench
+            }
             Some("setoption") => self.parse_setoption()?,
             Some("position") => self.parse_position()?,
             Some("go") => self.parse_go()?,
@@ -800,20 +1169,24 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_bestmove(&mut self) -> Result<UciOut, ProtocolError> {
-        Ok(UciOut::Bestmove {
+        // Engines report terminal positions as (none) or as the null move.
+        // Both are normalized to no move.
+        let bestmove = UciOut::Bestmove {
             m: match self.next() {
-                Some("(none)") | None => None,
+                Some("(none)" | "0000") | None => None,
                 Some(m) => Some(m.parse()?),
             },
             ponder: match self.next() {
                 Some("ponder") => match self.next() {
-                    Some("(none)") | None => None,
+                    Some("(none)" | "0000") | None => None,
                     Some(m) => Some(m.parse()?),
                 },
                 Some(_) => return Err(ProtocolError::UnexpectedToken),
                 None => None,
             },
-        })
+        };
+        self.end_strict()?;
+        Ok(bestmove)
     }
 
     fn parse_score(&mut self) -> Result<Score, ProtocolError> {
@@ -846,10 +1219,28 @@ impl<'a> Parser<'a> {
                 _ => break,
             }
         }
+        let wdl = match self.peek() {
+            Some("wdl") => {
+                self.next();
+                let mut permille = || -> Result<u32, ProtocolError> {
+                    Ok(self
+                        .next()
+                        .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                        .parse()?)
+                };
+                Some(Wdl {
+                    wins: permille()?,
+                    draws: permille()?,
+                    losses: permille()?,
+                })
+            }
+            _ => None,
+        };
         Ok(Score {
             eval,
             lowerbound,
             upperbound,
+            wdl,
         })
     }
 
@@ -867,6 +1258,7 @@ impl<'a> Parser<'a> {
         let mut tbhits = None;
         let mut sbhits = None;
         let mut cpuload = None;
+        let mut movesleft = None;
         let mut refutation = HashMap::new();
         let mut currline = HashMap::new();
         let mut pv = None;
@@ -958,6 +1350,13 @@ impl<'a> Parser<'a> {
                             .parse()?,
                     )
                 }
+                Some("movesleft") => {
+                    movesleft = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
                 Some("refutation") => {
                     refutation.insert(
                         self.next()
@@ -974,11 +1373,20 @@ impl<'a> Parser<'a> {
                         self.parse_moves(),
                     );
                 }
-                Some("pv") => pv = Some(self.parse_moves()),
+                Some("pv") => pv = Some(self.parse_pv()?),
                 Some("string") => {
                     string = Some(self.until(|_| false).unwrap_or_default().to_owned())
                 }
-                Some(_) => return Err(ProtocolError::UnexpectedToken),
+                Some(_) if self.mode == ParseMode::Strict => {
+                    return Err(ProtocolError::UnexpectedToken)
+                }
+                Some(_) => {
+                    // Nonstandard extension (for example ebf). Skip its
+                    // values, if any, up to the next key we know.
+                    if !self.peek().is_some_and(|token| INFO_KEYS.contains(&token)) {
+                        self.until(|token| INFO_KEYS.contains(&token));
+                    }
+                }
                 None => break,
             }
         }
@@ -996,6 +1404,7 @@ impl<'a> Parser<'a> {
             tbhits,
             sbhits,
             cpuload,
+            movesleft,
             refutation,
             currline,
             pv,
@@ -1020,15 +1429,103 @@ impl<'a> Parser<'a> {
         })
     }
 
+    fn parse_perft(&mut self) -> Result<UciOut, ProtocolError> {
+        match self.next() {
+            Some("searched:") => (),
+            #[cfg(feature = "stockfish")]
+            Some("searched") => {
+                return self
+                    .parse_bench(BenchStat::Nodes, &[])
+                    .ok_or(ProtocolError::UnexpectedToken)
+            }
+            Some(_) => return Err(ProtocolError::UnexpectedToken),
+            None => return Err(ProtocolError::UnexpectedEndOfLine),
+        }
+        let nodes = self
+            .next()
+            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+            .parse()?;
+        self.end()?;
+        Ok(UciOut::Perft { nodes })
+    }
+
+    /// Parses the rest of a line like `NNUE evaluation +0.10 (white side)`
+    /// or `Final evaluation: none (in check)`. Other lines starting with the
+    /// same word are not recognized.
+    #[cfg(feature = "stockfish")]
+    fn parse_eval(&mut self, kind: EvalKind) -> Option<UciOut> {
+        let value = match self.next()? {
+            "evaluation" => self.next()?,
+            "evaluation:" => self.next()?,
+            _ => return None,
+        };
+        let cp = match value {
+            "none" => None,
+            value => Some((value.parse::<f64>().ok()? * 100.0).round() as i64),
+        };
+        Some(UciOut::Eval { kind, cp })
+    }
+
+    /// Parses the rest of a line like `Total time (ms) : 1234`, after the
+    /// first word: the remaining `words` of the label, a colon and the
+    /// value. Other lines are not recognized.
+    #[cfg(feature = "stockfish")]
+    fn parse_bench(&mut self, stat: BenchStat, words: &[&str]) -> Option<UciOut> {
+        for word in words {
+            if self.next()? != *word {
+                return None;
+            }
+        }
+        if self.next()? != ":" {
+            return None;
+        }
+        let value = self.next()?.parse().ok()?;
+        if self.peek().is_some() {
+            return None;
+        }
+        Some(UciOut::Bench { stat, value })
+    }
+
     fn parse_out(&mut self) -> Result<Option<UciOut>, ProtocolError> {
         Ok(Some(match self.next() {
             Some("id") => self.parse_id()?,
-            Some("uciok") => UciOut::Uciok,
-            Some("readyok") => UciOut::Readyok,
+            Some("uciok") => {
+                self.end_strict()?;
+                UciOut::Uciok
+            }
+            Some("readyok") => {
+                self.end_strict()?;
+                UciOut::Readyok
+            }
             Some("bestmove") => self.parse_bestmove()?,
             Some("info") => self.parse_info()?,
             Some("option") => self.parse_option()?,
-            Some(_) | None => return Ok(None),
+            Some("Nodes") => self.parse_perft()?,
+            #[cfg(feature = "stockfish")]
+            Some(token @ ("Classical" | "NNUE" | "Final")) => {
+                let kind = match token {
+                    "Classical" => EvalKind::Classical,
+                    "NNUE" => EvalKind::Nnue,
+                    _ => EvalKind::Final,
+                };
+                return Ok(self.parse_eval(kind));
+            }
+            #[cfg(feature = "stockfish")]
+            Some("Total") => return Ok(self.parse_bench(BenchStat::TotalTime, &["time", "(ms)"])),
+            #[cfg(feature = "stockfish")]
+            Some("Nodes/second") => return Ok(self.parse_bench(BenchStat::Nps, &[])),
+            Some(token) => {
+                // Per move breakdown of perft, otherwise unrecognized.
+                match (
+                    token.strip_suffix(':').map(str::parse::<Uci>),
+                    self.next().map(str::parse::<u64>),
+                    self.peek(),
+                ) {
+                    (Some(Ok(m)), Some(Ok(nodes)), None) => UciOut::PerftMove { m, nodes },
+                    _ => return Ok(None),
+                }
+            }
+            None => return Ok(None),
         }))
     }
 }
@@ -1115,6 +1612,20 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_setoption_button() {
+        let command = UciIn::Setoption {
+            name: UciOptionName("Clear Hash".to_owned()),
+            value: None,
+        };
+        assert_eq!(command.to_string(), "setoption name Clear Hash");
+        assert_eq!(
+            UciOption::Button.validate(None).ok(),
+            Some(UciOptionValue::Button)
+        );
+        assert!(UciOption::Button.validate(Some("true".to_owned())).is_err());
+    }
+
     #[test]
     fn test_position() -> Result<(), ProtocolError> {
         assert!(matches!(
@@ -1141,4 +1652,466 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_option_clamp() -> Result<(), ProtocolError> {
+        let Some(UciOut::Option { option, .. }) =
+            UciOut::from_line("option name Hash type spin default 16 min 1 max 1024")?
+        else {
+            panic!("expected option");
+        };
+        assert_eq!(option.clamp(0), 1);
+        assert_eq!(option.clamp(4096), 1024);
+
+        let Some(UciOut::Option { mut option, .. }) =
+            UciOut::from_line("option name Hash type spin default 16 min 1024 max 1")?
+        else {
+            panic!("expected option");
+        };
+        assert_eq!(option.clamp(4096), 4096);
+        option.limit_max(8);
+        assert_eq!(option.max(), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_depth_seldepth() -> Result<(), ProtocolError> {
+        assert!(matches!(
+            UciOut::from_line("info depth 20 seldepth 31 nodes 1000")?,
+            Some(UciOut::Info {
+                depth: Some(20),
+                seldepth: Some(31),
+                ..
+            })
+        ));
+        assert!(matches!(
+            UciOut::from_line("info seldepth 31")?,
+            Some(UciOut::Info {
+                depth: None,
+                seldepth: Some(31),
+                ..
+            })
+        ));
+        assert!(matches!(
+            UciOut::from_line("info depth 20")?,
+            Some(UciOut::Info {
+                depth: Some(20),
+                seldepth: None,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_lichess_line() -> Result<(), ProtocolError> {
+        let lichess_line = |line: &str| {
+            UciOut::from_line(line)
+                .expect("valid output")
+                .expect("non-empty")
+                .to_lichess_line()
+        };
+        assert_eq!(
+            lichess_line(
+                "info nodes 1000 time 20 pv e2e4 e7e5 score cp 20 wdl 100 800 100 depth 15 hashfull 3"
+            )
+            .as_deref(),
+            Some("info depth 15 multipv 1 score cp 20 nodes 1000 time 20 pv e2e4 e7e5")
+        );
+        assert_eq!(
+            lichess_line("info depth 15 multipv 2 score cp 10 upperbound pv d2d4").as_deref(),
+            Some("info depth 15 multipv 2 score cp 10 upperbound pv d2d4")
+        );
+        assert_eq!(
+            lichess_line("info depth 15 score cp 10 lowerbound pv d2d4"),
+            None
+        );
+        assert_eq!(lichess_line("info depth 0 score mate 0"), None);
+        assert_eq!(lichess_line("info depth 1 score mate 0 pv e2e4"), None);
+        assert_eq!(lichess_line("info string hello"), None);
+        assert_eq!(lichess_line("readyok"), None);
+        assert_eq!(
+            lichess_line("bestmove e2e4 ponder e7e5").as_deref(),
+            Some("bestmove e2e4")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_score_white_pov() {
+        let score = |line: &str| match UciOut::from_line(line) {
+            Ok(Some(UciOut::Info {
+                score: Some(score), ..
+            })) => score,
+            _ => panic!("expected score: {line}"),
+        };
+        let black = score("info score cp 35 lowerbound wdl 300 600 100");
+        assert_eq!(black.white_pov(Color::White), black);
+        let white = black.white_pov(Color::Black);
+        assert_eq!(white, score("info score cp -35 upperbound wdl 100 600 300"));
+        assert_eq!(white.white_pov(Color::Black), black);
+        assert_eq!(
+            score("info score mate 3").white_pov(Color::Black),
+            score("info score mate -3")
+        );
+    }
+
+    #[test]
+    fn test_info_field_order() -> Result<(), ProtocolError> {
+        let expected = UciOut::from_line(
+            "info depth 15 seldepth 21 multipv 1 score cp 20 nodes 1000 nps 50000 time 20 pv e2e4 e7e5 g1f3",
+        )?;
+        assert!(matches!(
+            expected,
+            Some(UciOut::Info {
+                depth: Some(15),
+                nodes: Some(1000),
+                pv: Some(ref pv),
+                ..
+            }) if pv.len() == 3
+        ));
+
+        for line in [
+            "info depth 15 seldepth 21 time 20 nodes 1000 score cp 20 nps 50000 multipv 1 pv e2e4 e7e5 g1f3",
+            "info score cp 20 depth 15 nodes 1000 pv e2e4 e7e5 g1f3 seldepth 21 time 20 nps 50000 multipv 1",
+            "info nodes 1000 time 20 nps 50000 multipv 1 depth 15 seldepth 21 score cp 20 pv e2e4 e7e5 g1f3",
+            "info pv e2e4 e7e5 g1f3 multipv 1 nps 50000 time 20 nodes 1000 score cp 20 seldepth 21 depth 15",
+        ] {
+            assert_eq!(UciOut::from_line(line)?, expected, "{line}");
+        }
+
+        assert!(matches!(
+            UciOut::from_line("info currline 1 e2e4 e7e5 depth 3 string hello depth 4")?,
+            Some(UciOut::Info {
+                depth: Some(3),
+                string: Some(ref string),
+                ref currline,
+                ..
+            }) if string == "hello depth 4" && currline[&1].len() == 2
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_option_descriptor() -> Result<(), ProtocolError> {
+        for (line, kind, default, min, max, vars) in [
+            (
+                "option name Ponder type check default false",
+                OptionKind::Check,
+                Some("false"),
+                None,
+                None,
+                &[][..],
+            ),
+            (
+                "option name Hash type spin default 16 min 1 max 33554432",
+                OptionKind::Spin,
+                Some("16"),
+                Some(1),
+                Some(33554432),
+                &[][..],
+            ),
+            (
+                "option name UCI_Variant type combo default chess var chess var atomic",
+                OptionKind::Combo,
+                Some("chess"),
+                None,
+                None,
+                &["chess", "atomic"][..],
+            ),
+            (
+                "option name Clear Hash type button",
+                OptionKind::Button,
+                None,
+                None,
+                None,
+                &[][..],
+            ),
+            (
+                "option name Debug Log File type string default ",
+                OptionKind::String,
+                Some(""),
+                None,
+                None,
+                &[][..],
+            ),
+        ] {
+            let parsed = UciOut::from_line(line)?.expect("option");
+            assert_eq!(
+                UciOut::from_line(&parsed.to_string())?.as_ref(),
+                Some(&parsed)
+            );
+            let UciOut::Option { name, option } = parsed else {
+                panic!("expected option: {line}");
+            };
+            let descriptor = option.descriptor(&name);
+            assert_eq!(descriptor.name, name.0);
+            assert_eq!(descriptor.kind, kind);
+            assert_eq!(descriptor.default.as_deref(), default);
+            assert_eq!(descriptor.min, min);
+            assert_eq!(descriptor.max, max);
+            assert_eq!(descriptor.vars, vars);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_extensions() -> Result<(), ProtocolError> {
+        match UciOut::from_line("info depth 12 ebf 1.52 movesleft 37 wtf nodes 1000 pv e2e4")? {
+            Some(UciOut::Info {
+                depth,
+                movesleft,
+                nodes,
+                pv,
+                ..
+            }) => {
+                assert_eq!(depth, Some(12));
+                assert_eq!(movesleft, Some(37));
+                assert_eq!(nodes, Some(1000));
+                assert_eq!(pv, Some(vec!["e2e4".parse()?]));
+            }
+            other => panic!("expected info: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "stockfish")]
+    #[test]
+    fn test_eval() -> Result<(), ProtocolError> {
+        assert_eq!(UciIn::from_line("eval")?, Some(UciIn::Eval));
+        for (line, kind, cp) in [
+            (
+                "NNUE evaluation        +0.10 (white side)",
+                EvalKind::Nnue,
+                Some(10),
+            ),
+            (
+                "Final evaluation       -1.05 (white side) [with scaled NNUE, optimism, ...]",
+                EvalKind::Final,
+                Some(-105),
+            ),
+            ("Final evaluation: none (in check)", EvalKind::Final, None),
+        ] {
+            let eval = UciOut::from_line(line)?;
+            assert_eq!(eval, Some(UciOut::Eval { kind, cp }));
+            assert_eq!(
+                UciOut::from_line(&eval.expect("eval").to_string())?,
+                Some(UciOut::Eval { kind, cp })
+            );
+        }
+        assert_eq!(
+            UciOut::from_line("NNUE network contributions (White to move)")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "stockfish")]
+    #[test]
+    fn test_bench() -> Result<(), ProtocolError> {
+        assert_eq!(UciIn::from_line("bench")?, Some(UciIn::Bench));
+        for (line, stat, value) in [
+            ("Total time (ms) : 4210", BenchStat::TotalTime, 4210),
+            ("Nodes searched  : 2138002", BenchStat::Nodes, 2138002),
+            ("Nodes/second    : 507838", BenchStat::Nps, 507838),
+        ] {
+            let bench = UciOut::from_line(line)?;
+            assert_eq!(bench, Some(UciOut::Bench { stat, value }));
+            assert_eq!(bench.expect("bench").to_string(), line);
+        }
+        assert_eq!(
+            UciOut::from_line("Nodes searched: 8902")?,
+            Some(UciOut::Perft { nodes: 8902 })
+        );
+        assert_eq!(UciOut::from_line("Total nonsense")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_malformed_pv() -> Result<(), ProtocolError> {
+        let line = "info depth 10 pv e2e4 e7e5 x9z9 g1f3 nodes 1000";
+        match UciOut::from_line_with(line, ParseMode::Lenient)? {
+            Some(UciOut::Info { nodes, pv, .. }) => {
+                assert_eq!(nodes, Some(1000));
+                assert_eq!(pv, Some(vec!["e2e4".parse()?, "e7e5".parse()?]));
+            }
+            other => panic!("expected info: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_malformed_pv_strict() {
+        assert!(matches!(
+            UciOut::from_line_with(
+                "info depth 10 pv e2e4 e7e5 x9z9 g1f3 nodes 1000",
+                ParseMode::Strict
+            ),
+            Err(ProtocolError::InvalidMove(_))
+        ));
+    }
+
+    #[test]
+    fn test_info_wdl() -> Result<(), ProtocolError> {
+        let line = "info depth 20 score cp 30 wdl 54 932 14 pv e2e4";
+        match UciOut::from_line_with(line, ParseMode::Strict)? {
+            Some(
+                ref info @ UciOut::Info {
+                    score: Some(ref score),
+                    ..
+                },
+            ) => {
+                assert_eq!(
+                    score.wdl(),
+                    Some(Wdl {
+                        wins: 54,
+                        draws: 932,
+                        losses: 14
+                    })
+                );
+                assert_eq!(info.to_string(), line);
+            }
+            other => panic!("expected info: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mode() -> Result<(), ProtocolError> {
+        for line in [
+            "info depth 12 ebf 1.52 pv e2e4",
+            "bestmove e2e4 ponder e7e5 extra",
+            "readyok now",
+        ] {
+            assert!(UciOut::from_line_with(line, ParseMode::Lenient)?.is_some());
+            assert!(UciOut::from_line_with(line, ParseMode::Strict).is_err());
+        }
+        assert!(UciOut::from_line_with("info depth 12 pv e2e4", ParseMode::Strict)?.is_some());
+        assert_eq!(
+            UciOut::from_line_with(
+                "Stockfish 15 by the Stockfish developers",
+                ParseMode::Strict
+            )?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bestmove_none() -> Result<(), ProtocolError> {
+        for line in ["bestmove (none)", "bestmove 0000", "bestmove"] {
+            assert_eq!(
+                UciOut::from_line(line)?,
+                Some(UciOut::Bestmove {
+                    m: None,
+                    ponder: None
+                })
+            );
+        }
+        assert_eq!(
+            UciOut::from_line("bestmove e2e4 ponder 0000")?,
+            Some(UciOut::Bestmove {
+                m: Some("e2e4".parse()?),
+                ponder: None
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_perft() -> Result<(), ProtocolError> {
+        let go = UciIn::from_line("go perft 3")?.expect("go");
+        assert!(matches!(go, UciIn::Go { perft: Some(3), .. }));
+        assert_eq!(go.to_string(), "go perft 3");
+
+        assert_eq!(
+            UciOut::from_line("e2e4: 600")?,
+            Some(UciOut::PerftMove {
+                m: "e2e4".parse()?,
+                nodes: 600
+            })
+        );
+        assert_eq!(
+            UciOut::from_line("Nodes searched: 8902")?,
+            Some(UciOut::Perft { nodes: 8902 })
+        );
+        assert_eq!(
+            UciOut::from_line("Stockfish 15 by the Stockfish developers")?,
+            None
+        );
+        assert_eq!(UciOut::from_line("e2e4: lots")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uci_move() {
+        use shakmaty::{Role, Square};
+
+        assert_eq!(
+            "e2e4".parse::<UciMove>().ok(),
+            Some(UciMove::Normal {
+                from: Square::E2,
+                to: Square::E4,
+                promotion: None
+            })
+        );
+        for (uci, role) in [
+            ("a7a8q", Role::Queen),
+            ("b2b1r", Role::Rook),
+            ("c7c8b", Role::Bishop),
+            ("h2h1n", Role::Knight),
+            ("e7e8k", Role::King),
+        ] {
+            match uci.parse::<UciMove>() {
+                Ok(UciMove::Normal {
+                    promotion: Some(promotion),
+                    ..
+                }) => assert_eq!(promotion, role),
+                other => panic!("expected promotion: {other:?}"),
+            }
+        }
+        assert_eq!(
+            "P@e4".parse::<UciMove>().ok(),
+            Some(UciMove::Put {
+                role: Role::Pawn,
+                to: Square::E4
+            })
+        );
+        assert_eq!("0000".parse::<UciMove>().ok(), Some(UciMove::Null));
+        for uci in ["e2e4", "e7e8q", "N@f3", "0000"] {
+            assert_eq!(uci.parse::<UciMove>().expect("valid").to_string(), uci);
+        }
+        for invalid in [
+            "", "e2", "e2e9", "i2e4", "e7e8x", "X@e4", "e2e4qq", "(none)",
+        ] {
+            assert!(invalid.parse::<UciMove>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_crazyhouse_drops() -> Result<(), ProtocolError> {
+        for line in [
+            "position startpos moves e2e4 d7d5 e4d5 d8d5 P@e4 d5e4",
+            "position fen r1bqkbnr/pppppppp/2n5/8/8/5N2/PPPPPPPP/RNBQKB1R[Pp] w KQkq - 2 3 moves P@e4 p@d5",
+            "go searchmoves N@f3 P@e4 e2e4 depth 5",
+        ] {
+            let command = UciIn::from_line(line)?.expect("command");
+            let expected = line.replace("p@d5", "P@d5");
+            assert_eq!(command.to_string(), expected);
+            assert_eq!(UciIn::from_line(&expected)?, Some(command));
+        }
+
+        match UciIn::from_line("position startpos moves e2e4 d7d5 e4d5 d8d5 Q@e4")? {
+            Some(UciIn::Position { moves, .. }) => assert_eq!(
+                moves.last(),
+                Some(&UciMove::Put {
+                    role: shakmaty::Role::Queen,
+                    to: shakmaty::Square::E4
+                })
+            ),
+            other => panic!("expected position: {other:?}"),
+        }
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     io,
     iter::zip,
     sync::{
@@ -20,28 +21,142 @@ use rand::random;
 use serde::{Deserialize, Serialize};
 use tokio::{
     sync::{Mutex, MutexGuard, Notify},
-    time::{interval, MissedTickBehavior},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
 };
 
 use crate::{
-    engine::{Engine, Session},
-    uci::{UciIn, UciOut},
+    engine::{BreakerState, Engine, Session},
+    protocol::ProviderMessage,
+    uci::{UciIn, UciOptionName, UciOut},
 };
 
 pub struct SharedEngine {
     session: AtomicU64,
     notify: Notify,
+    sessions: std::sync::Mutex<Sessions>,
     engine: Mutex<Engine>,
 }
 
+#[derive(Default)]
+struct Sessions {
+    active: HashSet<Session>,
+    cancelled: HashSet<Session>,
+    /// Sessions whose option overlays can be forgotten, the next time the
+    /// engine is locked.
+    ended: Vec<Session>,
+}
+
 impl SharedEngine {
     pub fn new(engine: Engine) -> SharedEngine {
         SharedEngine {
             session: AtomicU64::new(0),
             notify: Notify::new(),
+            sessions: std::sync::Mutex::new(Sessions::default()),
             engine: Mutex::new(engine),
         }
     }
+
+    /// The most recently started session.
+    pub fn current_session(&self) -> Session {
+        Session(self.session.load(Ordering::SeqCst))
+    }
+
+    /// Whether a session is currently holding the engine.
+    pub fn is_in_use(&self) -> bool {
+        self.engine.try_lock().is_err()
+    }
+
+    /// State of the crash breaker, or `None` if a session is holding the
+    /// engine.
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.engine
+            .try_lock()
+            .ok()
+            .map(|engine| engine.breaker_state())
+    }
+
+    /// Asks the current session to stop searching and release the engine.
+    pub fn end_session(&self) -> Session {
+        let session = Session(self.session.fetch_add(1, Ordering::SeqCst) + 1);
+        self.notify.notify_one();
+        session
+    }
+
+    /// Sessions of connected clients that have not released the engine yet.
+    pub fn active_sessions(&self) -> Vec<Session> {
+        let mut active: Vec<_> = self.lock_sessions().active.iter().copied().collect();
+        active.sort_by_key(|session| session.0);
+        active
+    }
+
+    /// Stops any search of `session`, and disconnects its client once the
+    /// engine is idle. Returns `false` (and does nothing) if the session is
+    /// not active.
+    pub fn cancel_session(&self, session: Session) -> bool {
+        {
+            let mut sessions = self.lock_sessions();
+            if !sessions.active.contains(&session) {
+                return false;
+            }
+            sessions.cancelled.insert(session);
+        }
+        if session == self.current_session() {
+            self.end_session();
+        }
+        true
+    }
+
+    fn lock_sessions(&self) -> std::sync::MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn register(&self, session: Session) {
+        self.lock_sessions().active.insert(session);
+    }
+
+    /// Returns whether the session was cancelled.
+    fn unregister(&self, session: Session) -> bool {
+        let mut sessions = self.lock_sessions();
+        sessions.active.remove(&session);
+        sessions.ended.push(session);
+        sessions.cancelled.remove(&session)
+    }
+
+    fn take_ended(&self) -> Vec<Session> {
+        std::mem::take(&mut self.lock_sessions().ended)
+    }
+}
+
+struct Registration<'a> {
+    shared_engine: &'a SharedEngine,
+    session: Option<Session>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.shared_engine.unregister(session);
+        }
+    }
+}
+
+/// Periodically quits the engine if no session has used it for `ttl`.
+/// It is restarted when the next session begins.
+pub async fn reap_idle(shared_engine: Arc<SharedEngine>, ttl: Duration) {
+    let mut timer = interval(ttl / 4);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        timer.tick().await;
+        if let Ok(mut engine) = shared_engine.engine.try_lock() {
+            if !engine.is_exited() && engine.is_idle() && engine.idle_for() >= ttl {
+                let session = Session(shared_engine.session.load(Ordering::SeqCst));
+                log::warn!("{}: quitting idle engine", session.0);
+                if let Err(err) = engine.quit(session).await {
+                    log::error!("{}: failed to quit idle engine: {}", session.0, err);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Eq, Serialize, Deserialize, Clone, Debug)]
@@ -68,21 +183,102 @@ impl PartialEq for Secret {
     }
 }
 
+/// Coalesces analysis updates, so that at most one batch (the latest line
+/// for each multipv) is forwarded per interval. Other output, in particular
+/// `bestmove`, is forwarded immediately.
+pub struct InfoThrottle {
+    interval: Duration,
+    last_flush: Option<Instant>,
+    pending: BTreeMap<u32, UciOut>,
+}
+
+impl InfoThrottle {
+    pub fn new(interval: Duration) -> InfoThrottle {
+        InfoThrottle {
+            interval,
+            last_flush: None,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the lines that should be forwarded now.
+    pub fn push(&mut self, command: UciOut) -> Vec<UciOut> {
+        match command {
+            UciOut::Info {
+                multipv,
+                pv: Some(_),
+                ..
+            }
+            | UciOut::Info {
+                multipv,
+                score: Some(_),
+                ..
+            } => {
+                self.pending.insert(multipv.map_or(1, |n| n.get()), command);
+                self.flush_due()
+            }
+            UciOut::Info {
+                string: Some(_), ..
+            } => {
+                // Coalesced like analysis, but kept apart from multipv 1.
+                self.pending.insert(0, command);
+                self.flush_due()
+            }
+            UciOut::Bestmove { .. } => {
+                let mut lines = self.flush();
+                lines.push(command);
+                self.last_flush = None;
+                lines
+            }
+            _ => vec![command],
+        }
+    }
+
+    fn flush_due(&mut self) -> Vec<UciOut> {
+        match self.deadline() {
+            Some(deadline) if deadline > Instant::now() => Vec::new(),
+            _ => self.flush(),
+        }
+    }
+
+    /// When pending lines are due to be flushed.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(
+                self.last_flush
+                    .map_or_else(Instant::now, |t| t + self.interval),
+            )
+        }
+    }
+
+    pub fn flush(&mut self) -> Vec<UciOut> {
+        self.last_flush = Some(Instant::now());
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
 pub async fn handler(
     engine: Arc<SharedEngine>,
     secret: Secret,
+    info_interval: Duration,
     Query(params): Query<Params>,
     ws: WebSocketUpgrade,
 ) -> Result<impl IntoResponse, StatusCode> {
     if secret == params.secret {
-        Ok(ws.on_upgrade(move |socket| handle_socket(engine, socket)))
+        Ok(ws.on_upgrade(move |socket| handle_socket(engine, info_interval, socket)))
     } else {
         Err(StatusCode::FORBIDDEN)
     }
 }
 
-async fn handle_socket(shared_engine: Arc<SharedEngine>, mut socket: WebSocket) {
-    if let Err(err) = handle_socket_inner(&shared_engine, &mut socket).await {
+async fn handle_socket(
+    shared_engine: Arc<SharedEngine>,
+    info_interval: Duration,
+    mut socket: WebSocket,
+) {
+    if let Err(err) = handle_socket_inner(&shared_engine, info_interval, &mut socket).await {
         log::error!("handler: {}", err);
     }
     let _ = socket.send(Message::Close(None)).await;
@@ -93,15 +289,43 @@ enum Event {
     Socket(Option<Result<Message, axum::Error>>),
     Engine(io::Result<UciOut>),
     CheckSession,
+    Flush,
     Tick,
 }
 
+async fn send_uci(socket: &mut WebSocket, commands: Vec<UciOut>) -> io::Result<()> {
+    for command in commands {
+        let lines = match ProviderMessage::from_uci(&command) {
+            Some(message) => message.to_lines(),
+            None if matches!(command, UciOut::Info { .. } | UciOut::Bestmove { .. }) => continue,
+            // Replies to commands of the client, like options and readyok.
+            None => vec![command.to_string()],
+        };
+        for line in lines {
+            socket
+                .send(Message::Text(line))
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+        }
+    }
+    Ok(())
+}
+
 async fn handle_socket_inner(
     shared_engine: &SharedEngine,
+    info_interval: Duration,
     socket: &mut WebSocket,
 ) -> io::Result<()> {
     let mut locked_engine: Option<MutexGuard<Engine>> = None;
     let mut session = Session(0);
+    let mut throttle = InfoThrottle::new(info_interval);
+    // Options that the client set for itself, applied again whenever it
+    // starts a new session.
+    let mut overlay: HashMap<UciOptionName, Option<String>> = HashMap::new();
+    let mut registration = Registration {
+        shared_engine,
+        session: None,
+    };
 
     let mut missed_pong = false;
     let mut timeout = interval(Duration::from_secs(10));
@@ -120,6 +344,11 @@ async fn handle_socket_inner(
                 }
                 if engine.is_idle() {
                     log::warn!("{}: session ended", session.0);
+                    registration.session = None;
+                    if shared_engine.unregister(session) {
+                        log::warn!("{}: session cancelled", session.0);
+                        return Ok(());
+                    }
                 } else {
                     locked_engine = Some(engine);
                 }
@@ -129,11 +358,14 @@ async fn handle_socket_inner(
         }
 
         // Select next event to handle.
+        let flush_at = throttle.deadline();
         let event = if let Some(ref mut engine) = locked_engine {
             tokio::select! {
                 engine_in = socket.recv() => Event::Socket(engine_in),
                 engine_out = engine.recv(session) => Event::Engine(engine_out),
                 _ = shared_engine.notify.notified() => Event::CheckSession,
+                _ = sleep_until(flush_at.unwrap_or_else(Instant::now)),
+                    if flush_at.is_some() => Event::Flush,
                 _ = timeout.tick() => Event::Tick,
             }
         } else {
@@ -147,6 +379,8 @@ async fn handle_socket_inner(
         match event {
             Event::CheckSession => continue,
 
+            Event::Flush => send_uci(socket, throttle.flush()).await?,
+
             Event::Tick => {
                 if missed_pong {
                     log::error!("{}: ping timeout", session.0);
@@ -181,16 +415,37 @@ async fn handle_socket_inner(
                             shared_engine.notify.notify_one();
                             let mut engine = shared_engine.engine.lock().await;
                             log::warn!("{}: new session started", session.0);
+                            shared_engine.register(session);
+                            registration.session = Some(session);
+                            for ended in shared_engine.take_ended() {
+                                engine.forget_session(ended);
+                            }
+                            if engine.is_exited() {
+                                engine.restart(session).await?;
+                            }
                             engine.ensure_newgame(session).await?;
+                            for (name, value) in &overlay {
+                                engine
+                                    .set_session_option(session, name.clone(), value.clone())
+                                    .await?;
+                            }
+                            engine.activate_session(session).await?;
 
-                            // TODO: Should track and restore options and
-                            // positions of the session. Not required for
-                            // lichess.org.
+                            // TODO: Should track and restore positions of the
+                            // session. Not required for lichess.org.
                             engine
                         }
                     };
 
-                    engine.send(session, command).await?;
+                    match command {
+                        UciIn::Setoption { name, value } if engine.is_overlay_option(&name) => {
+                            engine
+                                .set_session_option(session, name.clone(), value.clone())
+                                .await?;
+                            overlay.insert(name, value);
+                        }
+                        command => engine.send(session, command).await?,
+                    }
                     locked_engine = Some(engine);
                 }
             }
@@ -221,12 +476,7 @@ async fn handle_socket_inner(
                 return Err(io::Error::new(io::ErrorKind::BrokenPipe, err));
             }
 
-            Event::Engine(Ok(command)) => {
-                socket
-                    .send(Message::Text(command.to_string()))
-                    .await
-                    .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
-            }
+            Event::Engine(Ok(command)) => send_uci(socket, throttle.push(command)).await?,
             Event::Engine(Err(err)) => return Err(err),
         }
     }
@@ -0,0 +1,510 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::{self, Write},
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use remote_uci::{
+    engine::{
+        Connection, Engine, EngineError, EngineKind, EngineParameters, LogFormat, MemoryGuard,
+        OnOutOfRange, OptionError, OptionOverride, ProtocolState, Session, SpawnRetry, Timeouts,
+        Transport, UnknownOptionPolicy,
+    },
+    transcript::{replay, SessionRecorder},
+    uci::{UciIn, UciOptionName, UciOut},
+};
+use tokio::{net::TcpListener, process::Command};
+
+/// Builds tiny-uci, which is an example rather than a binary, so that it is
+/// not installed. Examples end up next to the directory of the test
+/// executable.
+fn tiny_uci() -> PathBuf {
+    static TINY_UCI: OnceLock<PathBuf> = OnceLock::new();
+    TINY_UCI
+        .get_or_init(|| {
+            let mut build = std::process::Command::new(env!("CARGO"));
+            build.args([
+                "build",
+                "--quiet",
+                "--example",
+                "tiny-uci",
+                "--manifest-path",
+            ]);
+            build.arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+            if !cfg!(debug_assertions) {
+                build.arg("--release");
+            }
+            for (feature, enabled) in [
+                ("validation", cfg!(feature = "validation")),
+                ("stockfish", cfg!(feature = "stockfish")),
+            ] {
+                if enabled {
+                    build.args(["--features", feature]);
+                }
+            }
+            assert!(
+                build.status().expect("run cargo").success(),
+                "failed to build tiny-uci"
+            );
+            let mut path = std::env::current_exe().expect("test executable");
+            path.pop();
+            path.set_file_name("examples");
+            path.push(format!("tiny-uci{}", std::env::consts::EXE_SUFFIX));
+            path
+        })
+        .clone()
+}
+
+/// Serves tiny-uci to a single TCP connection, and returns the address.
+async fn serve_tiny_uci() -> io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut child = Command::new(tiny_uci())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let mut stdout = child.stdout.take().expect("piped stdout");
+        let (mut read, mut write) = stream.into_split();
+        tokio::try_join!(
+            tokio::io::copy(&mut read, &mut stdin),
+            tokio::io::copy(&mut stdout, &mut write)
+        )?;
+        io::Result::Ok(())
+    });
+    Ok(addr)
+}
+
+fn params() -> EngineParameters {
+    EngineParameters {
+        args: Vec::new(),
+        sandbox: None,
+        kind: EngineKind::AlphaBeta,
+        max_threads: 2,
+        max_hash: 8,
+        max_hash_fraction: None,
+        blocked_options: HashSet::new(),
+        overlay_options: HashSet::new(),
+        on_out_of_range: OnOutOfRange::Reject,
+        unknown_option_policy: UnknownOptionPolicy::Ignore,
+        synthetic_bestmove_on_exit: false,
+        supports_ucinewgame: true,
+        always_send_stop: false,
+        auto_stop_on_go: false,
+        timeouts: Timeouts::default(),
+        log_format: LogFormat::Plain,
+        log_raw_lines: false,
+        quiet_options: false,
+        max_pv_length: None,
+        max_nodes: None,
+        ready_before_go: false,
+        sync_after_bestmove: false,
+        max_search_time: None,
+        min_search_time: None,
+        search_limit: None,
+        position_transform: None,
+        max_position_moves: None,
+        reject_illegal_bestmove: true,
+        rlimits: None,
+        spawn_retry: None,
+        crash_breaker: None,
+        memory_guard: None,
+        recorder: None,
+        warmup: false,
+    }
+}
+
+fn command(line: &str) -> UciIn {
+    UciIn::from_line(line)
+        .expect("valid command")
+        .expect("non-empty")
+}
+
+#[tokio::test]
+async fn test_handshake() -> io::Result<()> {
+    let mut options = HashMap::new();
+    options.insert(
+        UciOptionName("Hash".to_owned()),
+        OptionOverride::Required("4".to_owned()),
+    );
+    let engine = Engine::new(tiny_uci(), params(), options).await?;
+    assert_eq!(engine.name(), Some("tiny-uci"));
+    assert_eq!(engine.max_threads(), 2);
+    assert_eq!(engine.max_hash(), 8);
+    assert_eq!(engine.current_hash(), Some(4));
+    assert!(engine.can_accept_search());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_probe() -> io::Result<()> {
+    let probe = Engine::probe(tiny_uci(), params()).await?;
+    assert_eq!(probe.name.as_deref(), Some("tiny-uci"));
+
+    let mut options = HashMap::new();
+    options.insert(
+        UciOptionName("Hash".to_owned()),
+        OptionOverride::Value("8".to_owned()),
+    );
+    options.insert(UciOptionName("Threads".to_owned()), OptionOverride::Default);
+    assert!(probe.validate_options(&options).is_ok());
+
+    options.insert(
+        UciOptionName("Hahs".to_owned()),
+        OptionOverride::Value("8".to_owned()),
+    );
+    options.insert(
+        UciOptionName("Threads".to_owned()),
+        OptionOverride::Required("3".to_owned()),
+    );
+    let errors = probe.validate_options(&options).expect_err("invalid");
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .any(|err| matches!(err, OptionError::Unknown(name) if name.0 == "Hahs")));
+    assert!(errors
+        .iter()
+        .any(|err| matches!(err, OptionError::InvalidValue { name, .. } if name.0 == "Threads")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spawn_retry() {
+    let started = Instant::now();
+    let Err(err) = Engine::new(
+        PathBuf::from("/nonexistent/engine"),
+        EngineParameters {
+            spawn_retry: Some(SpawnRetry {
+                backoff: Duration::from_secs(10),
+                ..SpawnRetry::default()
+            }),
+            ..params()
+        },
+        HashMap::new(),
+    )
+    .await
+    else {
+        panic!("engine does not exist");
+    };
+    // Fails fast.
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn test_args() -> io::Result<()> {
+    let engine = Engine::new(
+        tiny_uci(),
+        EngineParameters {
+            args: vec!["--name".to_owned(), "tiny-uci; echo pwned".to_owned()],
+            ..params()
+        },
+        HashMap::new(),
+    )
+    .await?;
+    assert_eq!(engine.name(), Some("tiny-uci; echo pwned"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sandbox() -> io::Result<()> {
+    let engine = Engine::new(
+        tiny_uci(),
+        EngineParameters {
+            args: vec!["--name".to_owned(), "sandboxed".to_owned()],
+            sandbox: Some(vec!["env".to_owned(), "--".to_owned()]),
+            ..params()
+        },
+        HashMap::new(),
+    )
+    .await?;
+    assert_eq!(engine.name(), Some("sandboxed"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bestmove() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let session = Session(1);
+    engine.ensure_newgame(session).await?;
+    engine
+        .send(session, command("position startpos moves e2e4"))
+        .await?;
+    engine.send(session, command("go depth 1")).await?;
+    let mut infos = 0;
+    let m = loop {
+        match engine.recv(session).await? {
+            UciOut::Info { .. } => infos += 1,
+            UciOut::Bestmove { m, .. } => break m,
+            _ => (),
+        }
+    };
+    assert_eq!(infos, 1);
+    assert_eq!(m, engine.last_search_summary().pv.first().cloned());
+    assert!(m.is_some());
+
+    // Checkmate.
+    let m = engine
+        .analyze(
+            session,
+            command("position startpos moves f2f3 e7e5 g2g4 d8h4"),
+            command("go depth 1"),
+            false,
+        )
+        .await?;
+    assert_eq!(m, None);
+    assert!(engine.is_idle());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quit() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let session = Session(1);
+    assert!(engine.pid().is_some());
+    engine.quit(session).await?;
+    assert!(engine.is_exited());
+    assert_eq!(engine.pid(), None);
+    assert!(engine
+        .wait(session, None)
+        .await?
+        .is_some_and(|status| status.success()));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_memory_guard() -> io::Result<()> {
+    let mut engine = Engine::new(
+        tiny_uci(),
+        EngineParameters {
+            memory_guard: Some(MemoryGuard {
+                max_resident: 1,
+                interval: Duration::from_millis(200),
+            }),
+            ..params()
+        },
+        HashMap::new(),
+    )
+    .await?;
+    let session = Session(1);
+    assert!(engine.resident_memory().is_some_and(|rss| rss > 1));
+    let err = engine.recv(session).await.expect_err("out of memory");
+    assert!(matches!(
+        err.get_ref().and_then(|err| err.downcast_ref()),
+        Some(EngineError::OutOfMemory(_))
+    ));
+    assert!(engine.is_exited());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_swap_binary() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let session = Session(1);
+    let pid = engine.pid();
+    assert!(engine
+        .swap_binary(session, PathBuf::from("/nonexistent/engine"))
+        .await
+        .is_err());
+    // Still running the previous engine.
+    assert_eq!(engine.pid(), pid);
+    let m = engine
+        .analyze(
+            session,
+            command("position startpos"),
+            command("go depth 1"),
+            true,
+        )
+        .await?;
+    assert!(m.is_some());
+
+    engine.swap_binary(session, tiny_uci()).await?;
+    assert_ne!(engine.pid(), pid);
+    assert_eq!(engine.name(), Some("tiny-uci"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_tcp() -> io::Result<()> {
+    let addr = serve_tiny_uci().await?;
+    let mut engine = Engine::connect_tcp(addr, params(), HashMap::new()).await?;
+    assert_eq!(engine.name(), Some("tiny-uci"));
+    assert_eq!(engine.pid(), None);
+    let m = engine
+        .analyze(
+            Session(1),
+            command("position startpos"),
+            command("go depth 1"),
+            true,
+        )
+        .await?;
+    assert!(m.is_some());
+    Ok(())
+}
+
+/// Runs a fresh tiny-uci for every connection, counting them.
+#[derive(Debug, Default)]
+struct PipeTransport {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Transport for PipeTransport {
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Connection>> + Send + '_>> {
+        Box::pin(async move {
+            let mut child = Command::new(tiny_uci())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let stdin = child.stdin.take().expect("piped stdin");
+            let stdout = child.stdout.take().expect("piped stdout");
+            tokio::spawn(async move { child.wait().await });
+            self.connections.fetch_add(1, Ordering::SeqCst);
+            Ok::<Connection, io::Error>((Box::new(stdin), Box::new(stdout)))
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_connect_transport() -> io::Result<()> {
+    let transport = PipeTransport::default();
+    let connections = Arc::clone(&transport.connections);
+    let mut engine = Engine::connect(transport, params(), HashMap::new()).await?;
+    assert_eq!(engine.name(), Some("tiny-uci"));
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    engine.restart(Session(1)).await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    assert_eq!(engine.pid(), None);
+    Ok(())
+}
+
+#[cfg(feature = "stockfish")]
+#[tokio::test]
+async fn test_static_eval_unsupported() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let eval = engine
+        .static_eval(Session(1), command("position startpos"))
+        .await?;
+    assert_eq!(eval, Default::default());
+    Ok(())
+}
+
+#[cfg(feature = "stockfish")]
+#[tokio::test]
+async fn test_bench() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let session = Session(1);
+    let bench = engine.bench(session, Some(Duration::from_secs(10))).await?;
+    // The summary on stderr is not seen.
+    assert_eq!(bench.nodes, 2);
+    assert!(engine.is_idle());
+    let m = engine
+        .analyze(
+            session,
+            command("position startpos"),
+            command("go depth 1"),
+            true,
+        )
+        .await?;
+    assert!(m.is_some());
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_replay() -> io::Result<()> {
+    let buffer = SharedBuffer::default();
+    let mut engine = Engine::new(
+        tiny_uci(),
+        EngineParameters {
+            recorder: Some(SessionRecorder::new(buffer.clone())),
+            ready_before_go: true,
+            ..params()
+        },
+        HashMap::new(),
+    )
+    .await?;
+    let session = Session(1);
+    engine.send(session, command("position startpos")).await?;
+    engine.send(session, command("go depth 1")).await?;
+    while engine.is_searching() {
+        engine.recv(session).await?;
+    }
+    engine.send(session, UciIn::Isready).await?;
+    engine.ensure_idle(session).await?;
+
+    let transcript = String::from_utf8(buffer.0.lock().unwrap().clone())
+        .expect("utf-8")
+        .parse()?;
+    let state = |pending_uciok, pending_readyok, searching| ProtocolState {
+        pending_uciok,
+        pending_readyok,
+        searching,
+    };
+    let expected = [
+        state(1, 0, false), // uci
+        state(1, 0, false), // id name
+        state(1, 0, false), // id author
+        state(1, 0, false), // option name Threads
+        state(1, 0, false), // option name Hash
+        state(0, 0, false), // uciok
+        state(0, 0, false), // position
+        state(0, 1, false), // isready of ready_before_go
+        state(0, 0, false), // readyok
+        state(0, 0, true),  // go
+        state(0, 0, true),  // info
+        state(0, 0, false), // bestmove
+        state(0, 1, false), // isready
+        state(0, 0, false), // readyok
+    ];
+    replay(&transcript, params(), &expected).await?;
+    assert!(replay(&transcript, params(), &expected[1..]).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_perft() -> io::Result<()> {
+    let mut engine = Engine::new(tiny_uci(), params(), HashMap::new()).await?;
+    let session = Session(1);
+    assert_eq!(
+        engine
+            .perft(session, command("position startpos"), 3)
+            .await?,
+        8902
+    );
+    assert!(engine.is_idle());
+
+    // Unbounded, so only through Engine::perft().
+    assert!(engine
+        .perft(session, command("position startpos"), 8)
+        .await
+        .is_err());
+    assert!(engine.send(session, command("go perft 3")).await.is_err());
+    assert!(engine.is_idle());
+    Ok(())
+}
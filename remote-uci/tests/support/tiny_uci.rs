@@ -0,0 +1,104 @@
+//! Minimal UCI engine for integration tests. It answers every search
+//! immediately with the first legal move in the position, so that its output
+//! is deterministic. `--name <name>` overrides the reported name.
+
+use std::{
+    env,
+    io::{self, BufRead, Write},
+};
+
+use remote_uci::uci::UciIn;
+use shakmaty::{fen::Fen, perft, uci::Uci, CastlingMode, Chess, Position};
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout().lock();
+    let mut pos = Chess::default();
+    let mut args = env::args().skip(1);
+    let mut name = "tiny-uci".to_owned();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => name = args.next().unwrap_or_default(),
+            _ => eprintln!("unknown argument: {arg}"),
+        }
+    }
+
+    for line in stdin.lock().lines() {
+        let command = match UciIn::from_line(&line?) {
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(err) => {
+                writeln!(stdout, "info string {err}")?;
+                continue;
+            }
+        };
+        match command {
+            UciIn::Uci => {
+                writeln!(stdout, "id name {name}")?;
+                writeln!(stdout, "id author remote-uci")?;
+                writeln!(
+                    stdout,
+                    "option name Threads type spin default 1 min 1 max 4"
+                )?;
+                writeln!(stdout, "option name Hash type spin default 1 min 1 max 16")?;
+                writeln!(stdout, "uciok")?;
+            }
+            UciIn::Isready => writeln!(stdout, "readyok")?,
+            UciIn::Ucinewgame => pos = Chess::default(),
+            UciIn::Position { fen, moves } => pos = setup(fen, &moves).unwrap_or_default(),
+            UciIn::Go {
+                perft: Some(depth), ..
+            } => {
+                for m in &pos.legal_moves() {
+                    let mut child = pos.clone();
+                    child.play_unchecked(m);
+                    let nodes = perft(&child, depth.saturating_sub(1));
+                    writeln!(stdout, "{}: {nodes}", Uci::from_standard(m))?;
+                }
+                writeln!(stdout)?;
+                writeln!(stdout, "Nodes searched: {}", perft(&pos, depth))?;
+            }
+            UciIn::Go { .. } => match pos.legal_moves().first() {
+                Some(m) => {
+                    let m = Uci::from_standard(m);
+                    writeln!(stdout, "info depth 1 score cp 0 nodes 1 pv {m}")?;
+                    writeln!(stdout, "bestmove {m}")?;
+                }
+                None => writeln!(stdout, "bestmove (none)")?,
+            },
+            UciIn::Quit => break,
+            #[cfg(feature = "stockfish")]
+            UciIn::Eval => writeln!(stdout, "Unknown command: 'eval'")?,
+            #[cfg(feature = "stockfish")]
+            UciIn::Bench => {
+                // Like Stockfish, with the summary on stderr.
+                let mut pos = Chess::default();
+                for _ in 0..2 {
+                    let m = pos.legal_moves()[0].clone();
+                    let uci = Uci::from_standard(&m);
+                    writeln!(stdout, "info depth 1 score cp 0 nodes 1 pv {uci}")?;
+                    writeln!(stdout, "bestmove {uci}")?;
+                    pos.play_unchecked(&m);
+                }
+                eprintln!("Total time (ms) : 1");
+                eprintln!("Nodes searched  : 2");
+                eprintln!("Nodes/second    : 2000");
+            }
+            UciIn::Setoption { .. } | UciIn::Stop | UciIn::Ponderhit => (),
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn setup(fen: Option<Fen>, moves: &[Uci]) -> Option<Chess> {
+    let mut pos: Chess = match fen {
+        Some(fen) => fen.into_position(CastlingMode::Standard).ok()?,
+        None => Chess::default(),
+    };
+    for uci in moves {
+        let m = uci.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(pos)
+}